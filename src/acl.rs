@@ -0,0 +1,374 @@
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use anyhow::{bail, Error, Result};
+
+
+/// Подсеть в нотации CIDR: `10.0.0.0/8`, `::1/128`. Без префикса - один адрес
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4-mapped IPv6 адреса сравниваем как обычные IPv4
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<IpNetwork> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = match addr.parse() {
+            Ok(addr) => addr,
+            Err(_) => bail!("Invalid network address {:?}", addr),
+        };
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max_prefix => prefix,
+                _ => bail!("Invalid prefix length {:?} for {}", prefix, addr),
+            },
+            None => max_prefix,
+        };
+        // IPv4-mapped подсеть хранится как IPv4, если целиком лежит в ::ffff:0:0/96
+        match (addr, addr.to_canonical()) {
+            (IpAddr::V6(_), IpAddr::V4(v4)) if prefix >= 96 => Ok(IpNetwork { addr: IpAddr::V4(v4), prefix: prefix - 96 }),
+            _ => Ok(IpNetwork { addr, prefix }),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// Правило: подсеть и, при необходимости, диапазон портов назначения
+#[derive(Debug, Clone)]
+pub struct Rule {
+    action: Action,
+    network: IpNetwork,
+    ports: Option<RangeInclusive<u16>>,
+}
+
+impl Rule {
+    /// Разбирает `<cidr>`, `<cidr>:<port>` или `<cidr>:<from>-<to>`;
+    /// IPv6 подсеть с портом записывается в скобках: `[::1/128]:22`
+    pub fn parse(action: Action, s: &str) -> Result<Rule> {
+        let (network, ports) = if let Some(rest) = s.strip_prefix('[') {
+            match rest.split_once(']') {
+                Some((network, "")) => (network, None),
+                Some((network, ports)) => match ports.strip_prefix(':') {
+                    Some(ports) => (network, Some(ports)),
+                    None => bail!("Invalid rule {:?}: expected [network]:port", s),
+                },
+                None => bail!("Invalid rule {:?}: missing ']'", s),
+            }
+        } else if s.matches(':').count() == 1 {
+            let (network, ports) = s.split_once(':').unwrap();
+            (network, Some(ports))
+        } else {
+            (s, None)
+        };
+
+        let ports = match ports {
+            Some(ports) => Some(parse_ports(ports)?),
+            None => None,
+        };
+        Ok(Rule { action, network: network.parse()?, ports })
+    }
+
+    fn matches(&self, addr: &SocketAddr) -> bool {
+        self.network.contains(addr.ip())
+            && self.ports.as_ref().is_none_or(|ports| ports.contains(&addr.port()))
+    }
+}
+
+fn parse_ports(s: &str) -> Result<RangeInclusive<u16>> {
+    let (from, to) = s.split_once('-').unwrap_or((s, s));
+    match (from.parse::<u16>(), to.parse::<u16>()) {
+        (Ok(from), Ok(to)) if from <= to => Ok(from..=to),
+        _ => bail!("Invalid port or port range {:?}", s),
+    }
+}
+
+
+/// Список правил для адресов назначения. Правила проверяются по порядку,
+/// решает первое совпавшее. Если ни одно не совпало, адрес запрещён при наличии
+/// хотя бы одного разрешающего правила и разрешён в противном случае
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    rules: Vec<Rule>,
+}
+
+impl AccessControl {
+    pub fn new() -> AccessControl {
+        AccessControl::default()
+    }
+
+    pub fn push(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    pub fn allow(&mut self, rule: &str) -> Result<()> {
+        self.push(Rule::parse(Action::Allow, rule)?);
+        Ok(())
+    }
+
+    pub fn deny(&mut self, rule: &str) -> Result<()> {
+        self.push(Rule::parse(Action::Deny, rule)?);
+        Ok(())
+    }
+
+    pub fn is_allowed(&self, addr: &SocketAddr) -> bool {
+        match self.rules.iter().find(|rule| rule.matches(addr)) {
+            Some(rule) => rule.action == Action::Allow,
+            None => !self.rules.iter().any(|rule| rule.action == Action::Allow),
+        }
+    }
+}
+
+
+/// Набор адресов назначения для настроек, которые включаются только для
+/// отдельных целей. Правила те же, что у `AccessControl`, но без разрешений
+/// и запретов: адрес входит в набор, если совпало любое правило
+#[derive(Debug, Clone, Default)]
+pub struct TargetList {
+    rules: Vec<Rule>,
+}
+
+impl TargetList {
+    pub fn new() -> TargetList {
+        TargetList::default()
+    }
+
+    pub fn add(&mut self, rule: &str) -> Result<()> {
+        self.rules.push(Rule::parse(Action::Allow, rule)?);
+        Ok(())
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.rules.iter().any(|rule| rule.matches(addr))
+    }
+}
+
+
+/// Запрещённые имена назначения: шаблоны вида `*.internal`, `metadata.google.*`.
+/// `*` заменяет любую последовательность символов, включая точки; регистр и
+/// завершающая точка имени не учитываются. Шаблоны приводятся к одному виду
+/// при запуске, поэтому проверка - только сравнение байт
+#[derive(Debug, Clone, Default)]
+pub struct DomainMatcher {
+    patterns: Vec<Vec<u8>>,
+}
+
+impl DomainMatcher {
+    pub fn new() -> DomainMatcher {
+        DomainMatcher::default()
+    }
+
+    pub fn add(&mut self, pattern: &str) -> Result<()> {
+        let pattern = normalize_domain(pattern.as_bytes());
+        if pattern.is_empty() || pattern.iter().all(|&b| b == b'*') {
+            bail!("Domain pattern {:?} would match every name", String::from_utf8_lossy(&pattern));
+        }
+        self.patterns.push(pattern);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Имя из запроса как есть, байтами: клиенты присылают и не UTF-8.
+    /// IP адреса проверяются в текстовом виде, так что `169.254.*` закрывает
+    /// и запросы с адресом вместо имени
+    pub fn matches(&self, domain: &[u8]) -> bool {
+        if self.patterns.is_empty() {
+            return false;
+        }
+        let domain = normalize_domain(domain);
+        self.patterns.iter().any(|pattern| glob_match(pattern, &domain))
+    }
+}
+
+fn normalize_domain(domain: &[u8]) -> Vec<u8> {
+    let domain = domain.strip_suffix(b".").unwrap_or(domain);
+    domain.to_ascii_lowercase()
+}
+
+/// Сравнение с шаблоном, где `*` - любая последовательность. При несовпадении
+/// откатывается к последней звёздочке, поэтому работает за O(n * m) без рекурсии
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(s: &str) -> IpNetwork {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn prefix_bounds() {
+        assert!(network("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(network("::/0").contains(ip("2001:db8::1")));
+        assert!(network("192.0.2.1/32").contains(ip("192.0.2.1")));
+        assert!(!network("192.0.2.1/32").contains(ip("192.0.2.2")));
+        assert!(network("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!network("2001:db8::1/128").contains(ip("2001:db8::2")));
+        // Без префикса - один адрес
+        assert_eq!(network("192.0.2.1"), network("192.0.2.1/32"));
+        assert_eq!(network("::1"), network("::1/128"));
+    }
+
+    #[test]
+    fn non_aligned_network() {
+        let net = network("10.1.2.3/8");
+        assert!(net.contains(ip("10.0.0.0")));
+        assert!(net.contains(ip("10.255.255.255")));
+        assert!(!net.contains(ip("11.0.0.0")));
+        let net = network("2001:db8:ffff::1/32");
+        assert!(net.contains(ip("2001:db8::")));
+        assert!(!net.contains(ip("2001:db9::")));
+    }
+
+    #[test]
+    fn mapped_ipv6_matches_ipv4_rule() {
+        assert!(network("127.0.0.0/8").contains(ip("::ffff:127.0.0.1")));
+        assert!(network("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+        assert!(!network("::ffff:10.0.0.0/104").contains(ip("11.0.0.0")));
+        assert_eq!(network("::ffff:10.0.0.0/104"), network("10.0.0.0/8"));
+        assert!(network("::ffff:0.0.0.0/95").contains(ip("::fffe:0:1")));
+        // Семейства не смешиваются
+        assert!(!network("::/0").contains(ip("127.0.0.1")));
+        assert!(!network("0.0.0.0/0").contains(ip("::1")));
+    }
+
+    #[test]
+    fn invalid_prefix() {
+        for invalid in ["10.0.0.0/33", "::/129", "10.0.0.0/-1", "10.0.0.0/", "10.0.0/8", "example.com/8"] {
+            assert!(invalid.parse::<IpNetwork>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn rule_ports() {
+        let mut acl = AccessControl::new();
+        acl.deny("10.0.0.0/8:22").unwrap();
+        acl.deny("[::1/128]:1000-2000").unwrap();
+        assert!(!acl.is_allowed(&"10.1.1.1:22".parse().unwrap()));
+        assert!(acl.is_allowed(&"10.1.1.1:80".parse().unwrap()));
+        assert!(!acl.is_allowed(&"[::1]:1500".parse().unwrap()));
+        assert!(acl.is_allowed(&"[::1]:2001".parse().unwrap()));
+        assert!(Rule::parse(Action::Deny, "10.0.0.0/8:20-10").is_err());
+        assert!(Rule::parse(Action::Deny, "[::1/128").is_err());
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let mut acl = AccessControl::new();
+        acl.deny("10.0.0.1").unwrap();
+        acl.allow("10.0.0.0/8").unwrap();
+        assert!(!acl.is_allowed(&"10.0.0.1:80".parse().unwrap()));
+        assert!(acl.is_allowed(&"10.0.0.2:80".parse().unwrap()));
+        // Есть разрешающее правило - остальное запрещено
+        assert!(!acl.is_allowed(&"192.0.2.1:80".parse().unwrap()));
+    }
+
+    fn matcher(patterns: &[&str]) -> DomainMatcher {
+        let mut matcher = DomainMatcher::new();
+        for pattern in patterns {
+            matcher.add(pattern).unwrap();
+        }
+        matcher
+    }
+
+    #[test]
+    fn subdomain_wildcard() {
+        let matcher = matcher(&["*.example.com"]);
+        assert!(matcher.matches(b"www.example.com"));
+        assert!(matcher.matches(b"a.b.example.com"));
+        assert!(!matcher.matches(b"example.com"));
+        assert!(!matcher.matches(b"badexample.com"));
+        assert!(!matcher.matches(b"example.com.evil"));
+    }
+
+    #[test]
+    fn case_and_trailing_dot() {
+        let matcher = matcher(&["Metadata.Google.*", "localhost."]);
+        assert!(matcher.matches(b"metadata.google.internal"));
+        assert!(matcher.matches(b"METADATA.GOOGLE.INTERNAL."));
+        assert!(matcher.matches(b"LocalHost"));
+        assert!(matcher.matches(b"localhost."));
+        assert!(!matcher.matches(b"localhost.localdomain"));
+    }
+
+    #[test]
+    fn several_wildcards() {
+        let matcher = matcher(&["*.internal.*.corp", "169.254.*"]);
+        assert!(matcher.matches(b"db.internal.eu.corp"));
+        assert!(matcher.matches(b"a.b.internal.x.y.corp"));
+        assert!(!matcher.matches(b"internal.eu.corp"));
+        assert!(!matcher.matches(b"db.internal.corp"));
+        assert!(matcher.matches(b"169.254.169.254"));
+        assert!(!matcher.matches(b"10.169.254.1"));
+        assert!(glob_match(b"a*b*c", b"aXbYbZc"));
+        assert!(!glob_match(b"a*b*c", b"aXbYbZ"));
+        assert!(glob_match(b"**", b""));
+    }
+
+    #[test]
+    fn rejects_catch_all_patterns() {
+        let mut matcher = DomainMatcher::new();
+        for pattern in ["", ".", "*", "**."] {
+            assert!(matcher.add(pattern).is_err(), "{:?}", pattern);
+        }
+        assert!(matcher.is_empty());
+        assert!(!matcher.matches(b"example.com"));
+    }
+}
@@ -0,0 +1,132 @@
+use std::io;
+use std::net::SocketAddr;
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+use crate::parse::{parse_auth, AuthRequest, ParseError};
+use crate::SocksError;
+use crate::stream::ClientStream;
+use crate::{read_message, METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD};
+
+
+/// Сведения о клиенте, доступные методу аутентификации
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub peer_addr: SocketAddr,
+    pub local_addr: SocketAddr,
+}
+
+/// Результат подсогласования метода аутентификации
+#[derive(Debug)]
+pub enum AuthOutcome {
+    /// Клиент допущен; имя пользователя, если метод его устанавливает
+    Authenticated(Option<String>),
+    /// Клиент не прошёл проверку, соединение закрывается
+    Rejected,
+}
+
+/// Метод аутентификации SOCKS5 (RFC 1928, раздел 3). Метод выбирается в
+/// `process_method`, после чего `negotiate` проводит его подсогласование:
+/// читает данные клиента и отправляет ему ответ в формате метода
+#[async_trait]
+pub trait AuthMethod: Send + Sync {
+    /// Код метода в приветствии (METHOD)
+    fn id(&self) -> u8;
+
+    async fn negotiate(&self, client_stream: &mut dyn ClientStream, client: &ClientInfo) -> Result<AuthOutcome>;
+}
+
+
+/// Без аутентификации (0x00): подсогласования нет
+pub struct NoAuth;
+
+#[async_trait]
+impl AuthMethod for NoAuth {
+    fn id(&self) -> u8 {
+        METHOD_NO_AUTH
+    }
+
+    async fn negotiate(&self, _client_stream: &mut dyn ClientStream, _client: &ClientInfo) -> Result<AuthOutcome> {
+        Ok(AuthOutcome::Authenticated(None))
+    }
+}
+
+
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+/// Логин и пароль (0x02, RFC 1929)
+#[derive(Default)]
+pub struct UsernamePassword {
+    users: Vec<Credentials>,
+}
+
+impl UsernamePassword {
+    pub fn new() -> UsernamePassword {
+        UsernamePassword::default()
+    }
+
+    pub fn add_user(&mut self, username: impl Into<String>, password: impl Into<String>) {
+        let username = username.into();
+        let password = password.into();
+        assert!(
+            (1..=255).contains(&username.len()) && (1..=255).contains(&password.len()),
+            "Username and password must be 1 to 255 bytes long"
+        );
+        self.users.push(Credentials { username, password });
+    }
+
+    pub fn with_user(mut self, username: impl Into<String>, password: impl Into<String>) -> UsernamePassword {
+        self.add_user(username, password);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+#[async_trait]
+impl AuthMethod for UsernamePassword {
+    fn id(&self) -> u8 {
+        METHOD_USERNAME_PASSWORD
+    }
+
+    /// VER(0x01), ULEN, UNAME, PLEN, PASSWD -> VER, STATUS
+    async fn negotiate(&self, client_stream: &mut dyn ClientStream, _client: &ClientInfo) -> Result<AuthOutcome> {
+        let mut buf = Vec::new();
+        if let Err(e) = read_message(client_stream, &mut buf, |buf| parse_auth(buf).map(drop)).await {
+            // С другой версией остальные поля неизвестно как читать: отказываем сразу
+            if let Some(SocksError::UnsupportedVersion(version)) = e.downcast_ref() {
+                warn!("unsupported auth sub-negotiation version {:#04x}", version);
+                respond(client_stream, 0x01).await?;
+                return Ok(AuthOutcome::Rejected);
+            }
+            return Err(e.context("Auth request truncated"));
+        }
+        let AuthRequest { username, password } = parse_auth(&buf).map_err(ParseError::into_error)?;
+
+        // ULEN и PLEN по RFC 1929 не меньше 1: пустые поля не совпадают ни с кем
+        let user = self.users.iter().find(|user| {
+            !username.is_empty()
+                && !password.is_empty()
+                && username == user.username.as_bytes()
+                && password == user.password.as_bytes()
+        });
+
+        respond(client_stream, if user.is_some() { 0x00 } else { 0x01 }).await?;
+        Ok(match user {
+            Some(user) => AuthOutcome::Authenticated(Some(user.username.clone())),
+            None => AuthOutcome::Rejected,
+        })
+    }
+}
+
+
+async fn respond(client_stream: &mut dyn ClientStream, status: u8) -> io::Result<()> {
+    client_stream.write_all(&[0x01, status]).await?;
+    client_stream.flush().await
+}
@@ -0,0 +1,50 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use anyhow::Result;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout_at, Instant};
+use tracing::{info, warn};
+use crate::{canonical_ip, reply, SocksError, SOCKSReply};
+use crate::port_range::{self, PortRange};
+use crate::stream::ClientStream;
+
+
+/// Сколько ждать входящего соединения по умолчанию
+pub const DEFAULT_ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+
+/// BIND: открывает слушающий сокет, сообщает клиенту его адрес первым ответом,
+/// ждёт входящего соединения от `expected` и сообщает адрес подключившегося вторым ответом.
+/// Нулевой IP в `expected` означает, что принимается соединение с любого адреса.
+/// Порт выбирается из `ports`, если диапазон задан; когда он исчерпан - общая ошибка
+pub async fn process_bind(client_stream: &mut dyn ClientStream, expected: SocketAddr, accept_timeout: Duration, ports: Option<PortRange>) -> Result<TcpStream> {
+    // Слушаем на том же адресе, на который пришёл клиент, чтобы адрес из ответа был достижим
+    let local_ip = client_stream.local_addr()?.ip();
+    let listener = port_range::bind_in(ports, |port| TcpListener::bind((local_ip, port))).await?;
+    let bind_addr = listener.local_addr()?;
+    info!("bind listening on {}", bind_addr);
+    reply(client_stream, SOCKSReply::Succeeded, &bind_addr).await?;
+
+    let (peer_stream, peer_addr) = accept_from(&listener, expected, accept_timeout).await?;
+    info!("bind accepted {}", peer_addr);
+    reply(client_stream, SOCKSReply::Succeeded, &peer_addr).await?;
+    Ok(peer_stream)
+}
+
+
+/// Ждёт первое соединение с ожидаемого адреса; чужие соединения сразу закрываются
+async fn accept_from(listener: &TcpListener, expected: SocketAddr, timeout: Duration) -> Result<(TcpStream, SocketAddr)> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let (stream, peer_addr) = match timeout_at(deadline, listener.accept()).await {
+            Ok(accepted) => accepted?,
+            Err(_) => return Err(SocksError::Timeout("waiting for the incoming connection").into()),
+        };
+        // IPv4 на IPv6 сокете приходит как ::ffff:a.b.c.d
+        if canonical_ip(expected.ip()).is_unspecified() || canonical_ip(peer_addr.ip()) == canonical_ip(expected.ip()) {
+            return Ok((stream, peer_addr));
+        }
+        warn!("bind rejected unexpected peer {} (expected {})", peer_addr, expected.ip());
+        drop(stream);
+    }
+}
@@ -0,0 +1,270 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use anyhow::{bail, ensure, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use crate::obfs::{ObfsKey, XorStream};
+use crate::parse::Address;
+use crate::{push_socket_addr, AddressType, Command, SocksError, METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD};
+
+
+/// Клиент SOCKS5 без аутентификации, например для проверки сервера в
+/// тестах. Возвращает обычный `TcpStream`, уже соединённый с целью через прокси
+pub struct Socks5Stream;
+
+impl Socks5Stream {
+    /// CONNECT к `target` через прокси `proxy`
+    pub async fn connect(proxy: SocketAddr, target: SocketAddr) -> Result<TcpStream> {
+        handshake(proxy, Address::Ip(target), None).await
+    }
+
+    /// CONNECT к `host:port`: имя резолвит прокси, а не клиент
+    pub async fn connect_with_name(proxy: SocketAddr, host: &str, port: u16) -> Result<TcpStream> {
+        ensure!((1..=255).contains(&host.len()), "Host name must be 1 to 255 bytes long");
+        handshake(proxy, Address::Domain(host.as_bytes(), port), None).await
+    }
+
+    /// CONNECT к `target` через прокси с обфускацией (`SocksServer::with_obfuscation`)
+    /// тем же ключом. Поток остаётся обёрнутым: данные цели тоже идут через XOR
+    pub async fn connect_obfuscated(proxy: SocketAddr, target: SocketAddr, key: ObfsKey) -> Result<XorStream<TcpStream>> {
+        let mut stream = XorStream::new(TcpStream::connect(proxy).await?, key);
+        negotiate(&mut stream, proxy, Address::Ip(target), None).await?;
+        Ok(stream)
+    }
+}
+
+
+/// Открывает соединение с `target` через SOCKS5 прокси `proxy`. С `credentials`
+/// (имя, пароль) прокси может выбрать аутентификацию по RFC 1929, без них - только без аутентификации.
+/// `connect_timeout` ограничивает подключение к прокси вместе с рукопожатием;
+/// по его истечении возвращается ошибка `TimedOut`.
+/// После успешного ответа прокси поток готов к обмену данными с целью
+pub async fn socks5_connect_through(proxy: SocketAddr, target: Address<'_>, credentials: Option<(&str, &str)>, connect_timeout: Option<Duration>) -> Result<TcpStream> {
+    match connect_timeout {
+        Some(connect_timeout) => match timeout(connect_timeout, handshake(proxy, target, credentials)).await {
+            Ok(result) => result,
+            Err(_) => Err(SocksError::Timeout("connecting through the proxy").into()),
+        },
+        None => handshake(proxy, target, credentials).await,
+    }
+}
+
+async fn handshake(proxy: SocketAddr, target: Address<'_>, credentials: Option<(&str, &str)>) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    negotiate(&mut stream, proxy, target, credentials).await?;
+    Ok(stream)
+}
+
+/// Приветствие, аутентификация и CONNECT в уже открытом соединении с прокси
+async fn negotiate<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, proxy: SocketAddr, target: Address<'_>, credentials: Option<(&str, &str)>) -> Result<()> {
+    // Длина имени занимает один байт: длинное имя исказило бы запрос
+    if let Address::Domain(name, _) = target {
+        ensure!((1..=255).contains(&name.len()), "Host name must be 1 to 255 bytes long");
+    }
+
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[0x05, 0x02, METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD],
+        None => &[0x05, 0x01, METHOD_NO_AUTH],
+    };
+    stream.write_all(greeting).await?;
+    let mut method = [0; 2];
+    stream.read_exact(&mut method).await?;
+    if method[0] != 0x05 {
+        return Err(SocksError::MalformedUpstreamReply.into());
+    }
+    match (method[1], credentials) {
+        (METHOD_NO_AUTH, _) => {}
+        (METHOD_USERNAME_PASSWORD, Some((username, password))) => {
+            authenticate(stream, username, password).await?;
+        }
+        (method, _) => bail!("Proxy {} requires an unsupported auth method {:#04x}", proxy, method),
+    }
+
+    let mut request = vec![0x05, Command::Connect as u8, 0x00];
+    match target {
+        Address::Ip(addr) => push_socket_addr(&mut request, &addr),
+        Address::Domain(name, port) => {
+            request.extend_from_slice(&[AddressType::Domain as u8, name.len() as u8]);
+            request.extend_from_slice(name);
+            request.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    // VER, REP, RSV, ATYP, затем BND.ADDR и BND.PORT, которые нам не нужны
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    if header[0] != 0x05 {
+        return Err(SocksError::MalformedUpstreamReply.into());
+    }
+    if header[1] != 0x00 {
+        return Err(SocksError::UpstreamFailure(header[1]).into());
+    }
+    let atyp = AddressType::try_from(header[3]).map_err(|_| SocksError::MalformedUpstreamReply)?;
+    let addr_len = match atyp.addr_len() {
+        Some(len) => len,
+        None => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+    };
+    let mut bound_addr = vec![0; addr_len + 2];
+    stream.read_exact(&mut bound_addr).await?;
+    Ok(())
+}
+
+/// Подсогласование RFC 1929: VER(0x01), ULEN, UNAME, PLEN, PASSWD -> VER, STATUS
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, username: &str, password: &str) -> Result<()> {
+    ensure!(
+        (1..=255).contains(&username.len()) && (1..=255).contains(&password.len()),
+        "Username and password must be 1 to 255 bytes long"
+    );
+    let mut request = vec![0x01, username.len() as u8];
+    request.extend_from_slice(username.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let mut response = [0; 2];
+    stream.read_exact(&mut response).await?;
+    if response[1] != 0x00 {
+        bail!("Proxy rejected the credentials (status {:#04x})", response[1]);
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 1080))
+    }
+
+    #[tokio::test]
+    async fn rejects_long_host_name() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let name = vec![b'a'; 256];
+        assert!(negotiate(&mut client, proxy(), Address::Domain(&name, 443), None).await.is_err());
+        assert!(negotiate(&mut client, proxy(), Address::Domain(b"", 443), None).await.is_err());
+        // Прокси не получил ни байта
+        drop(client);
+        let mut sent = Vec::new();
+        server.read_to_end(&mut sent).await.unwrap();
+        assert!(sent.is_empty());
+    }
+
+    /// Прокси-заглушка: по очереди ждёт от клиента каждое сообщение сценария
+    /// байт в байт и отправляет на него свой ответ
+    async fn scripted_proxy(script: Vec<(Vec<u8>, Vec<u8>)>) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            for (expected, reply) in script {
+                let mut received = vec![0; expected.len()];
+                stream.read_exact(&mut received).await.unwrap();
+                assert_eq!(received, expected);
+                stream.write_all(&reply).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    fn target() -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], 443))
+    }
+
+    fn connect_request() -> Vec<u8> {
+        vec![0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0x01, 0xbb]
+    }
+
+    fn success() -> Vec<u8> {
+        vec![0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0x30, 0x39]
+    }
+
+    async fn connect(proxy: SocketAddr, credentials: Option<(&str, &str)>) -> Result<TcpStream> {
+        socks5_connect_through(proxy, Address::Ip(target()), credentials, Some(Duration::from_secs(5))).await
+    }
+
+    fn upstream_error(e: anyhow::Error) -> SocksError {
+        e.downcast().expect("SocksError")
+    }
+
+    #[tokio::test]
+    async fn no_auth() {
+        let proxy = scripted_proxy(vec![
+            (vec![0x05, 0x01, 0x00], vec![0x05, 0x00]),
+            (connect_request(), success()),
+        ]).await;
+        assert!(connect(proxy, None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn domain_target() {
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, 11];
+        request.extend_from_slice(b"example.com\x00\x50");
+        let proxy = scripted_proxy(vec![
+            (vec![0x05, 0x01, 0x00], vec![0x05, 0x00]),
+            (request, vec![0x05, 0x00, 0x00, 0x03, 4, b'h', b'o', b's', b't', 0x00, 0x50]),
+        ]).await;
+        socks5_connect_through(proxy, Address::Domain(b"example.com", 80), None, None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn username_password() {
+        let proxy = scripted_proxy(vec![
+            (vec![0x05, 0x02, 0x00, 0x02], vec![0x05, 0x02]),
+            (b"\x01\x04user\x06secret".to_vec(), vec![0x01, 0x00]),
+            (connect_request(), success()),
+        ]).await;
+        assert!(connect(proxy, Some(("user", "secret"))).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn credentials_rejected() {
+        let proxy = scripted_proxy(vec![
+            (vec![0x05, 0x02, 0x00, 0x02], vec![0x05, 0x02]),
+            (b"\x01\x04user\x05wrong".to_vec(), vec![0x01, 0x01]),
+        ]).await;
+        let e = connect(proxy, Some(("user", "wrong"))).await.unwrap_err();
+        assert!(e.to_string().contains("rejected the credentials"));
+        assert_eq!(crate::error_reply(&e) as u8, 0x01);
+    }
+
+    #[tokio::test]
+    async fn password_required_without_credentials() {
+        let proxy = scripted_proxy(vec![(vec![0x05, 0x01, 0x00], vec![0x05, 0x02])]).await;
+        assert!(connect(proxy, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn error_reply_passed_through() {
+        let proxy = scripted_proxy(vec![
+            (vec![0x05, 0x01, 0x00], vec![0x05, 0x00]),
+            (connect_request(), vec![0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0]),
+        ]).await;
+        let e = upstream_error(connect(proxy, None).await.unwrap_err());
+        assert!(matches!(e, SocksError::UpstreamFailure(0x05)));
+        assert_eq!(e.reply_code() as u8, 0x05);
+    }
+
+    #[tokio::test]
+    async fn malformed_reply_version() {
+        let proxy = scripted_proxy(vec![(vec![0x05, 0x01, 0x00], vec![0x04, 0x00])]).await;
+        assert!(matches!(upstream_error(connect(proxy, None).await.unwrap_err()), SocksError::MalformedUpstreamReply));
+
+        let proxy = scripted_proxy(vec![
+            (vec![0x05, 0x01, 0x00], vec![0x05, 0x00]),
+            (connect_request(), vec![0x00, 0x5a, 0x00, 0x00, 0, 0, 0, 0]),
+        ]).await;
+        let e = upstream_error(connect(proxy, None).await.unwrap_err());
+        assert!(matches!(e, SocksError::MalformedUpstreamReply));
+        assert_eq!(e.reply_code() as u8, 0x01);
+    }
+}
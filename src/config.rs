@@ -0,0 +1,804 @@
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Runtime;
+use tracing::{info, warn, Level};
+use socks_beta::{SocksServer, BUFFER_SIZE_RANGE, DEFAULT_ACCEPT_TIMEOUT, DEFAULT_BUFFER_SIZE, DEFAULT_HANDSHAKE_TIMEOUT, METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD};
+use socks_beta::acl::{AccessControl, DomainMatcher, TargetList};
+use socks_beta::conn_limit::ConnectionLimit;
+use socks_beta::keepalive::Keepalive;
+use socks_beta::log::AccessLogger;
+use socks_beta::port_range::PortRange;
+use socks_beta::{tls, udp};
+
+
+/// Порт по умолчанию, если он не задан ни в файле, ни в параметрах
+const DEFAULT_PORT: u16 = 9150;
+
+
+/// Учётная запись для аутентификации по имени и паролю
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    pub username: String,
+    pub password: String,
+}
+
+/// Дополнительный порт прослушивания. Пользователи, методы аутентификации
+/// и правила, если заданы, заменяют общие; остальное берётся из общих настроек
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PortConfig {
+    pub port: u16,
+    pub users: Option<Vec<User>>,
+    pub auth_methods: Option<Vec<String>>,
+    pub rules: Option<Vec<AclRule>>,
+}
+
+impl PortConfig {
+    fn new(port: u16) -> PortConfig {
+        PortConfig { port, users: None, auth_methods: None, rules: None }
+    }
+}
+
+/// Правило доступа в файле: `{ allow = "10.0.0.0/8" }` или `{ deny = "10.0.0.1:22" }`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AclRule {
+    Allow(String),
+    Deny(String),
+}
+
+
+/// Настройки сервера. Сначала читаются из TOML файла (`--config`),
+/// затем параметры командной строки перекрывают значения из файла.
+/// Схема файла описана в `config.example.toml`
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// IP или IP:порт для прослушивания
+    pub bind: Option<String>,
+    pub port: Option<u16>,
+    /// Порты прослушивания на адресе `bind`, каждый со своим сервером
+    pub ports: Vec<PortConfig>,
+    /// Рабочие потоки рантайма tokio (в каждом воркере)
+    pub threads: Option<usize>,
+    /// Независимые серверы на одном порту через SO_REUSEPORT
+    pub workers: Option<usize>,
+    pub users: Vec<User>,
+    /// Включённые методы аутентификации в порядке приоритета: none, password
+    pub auth_methods: Option<Vec<String>>,
+    pub allow_bind: bool,
+    /// Сколько BIND ждёт входящего соединения, мс
+    pub bind_timeout: Option<u64>,
+    /// Порты слушающих сокетов BIND, `first-last`
+    pub bind_port_range: Option<String>,
+    /// Таймаут подключения к цели, мс
+    pub connect_timeout: Option<u64>,
+    /// Общий таймаут на приветствие, аутентификацию и запрос, мс
+    pub handshake_timeout: Option<u64>,
+    /// Таймаут простоя ретранслируемого соединения, мс
+    pub idle_timeout: Option<u64>,
+    /// Скорость каждого направления соединения, байт в секунду
+    pub rate_limit: Option<u64>,
+    /// Размер буфера ретрансляции каждого направления, байт
+    pub buffer_size: Option<usize>,
+    /// Не выключать алгоритм Нейгла (TCP_NODELAY) на соединениях
+    pub no_nodelay: bool,
+    /// Принимать датаграммы UDP ASSOCIATE с IP клиента с любого порта,
+    /// не проверяя адрес из запроса
+    pub no_udp_source_check: bool,
+    /// Сколько UDP ассоциация помнит адрес назначения без обмена (секунды)
+    pub udp_peer_timeout: Option<u64>,
+    /// Сколько адресов назначения помнит одна UDP ассоциация
+    pub udp_max_peers: Option<usize>,
+    /// Наибольшая UDP датаграмма между клиентом и ретранслятором, байт
+    pub udp_max_datagram: Option<usize>,
+    /// IP ретранслятора UDP для клиентов, если прокси за NAT
+    pub udp_external_addr: Option<IpAddr>,
+    /// Порты ретранслятора UDP, `first-last`
+    pub udp_port_range: Option<String>,
+    /// Отправлять цели CONNECT заголовок PROXY protocol v1
+    pub emit_proxy_protocol: bool,
+    /// Правила целей (как в `rules`), которым отправляется заголовок PROXY protocol v2
+    pub proxy_protocol_v2_for: Vec<String>,
+    /// Отказывать клиентам, приславшим данные до ответа на запрос
+    pub strict_handshake: bool,
+    /// Сколько соединений одновременно принимать всего и с одного IP клиента
+    pub max_connections: Option<u32>,
+    pub max_conn_per_ip: Option<u32>,
+    /// Принимать CONNECT только к доменным именам
+    pub require_hostname: bool,
+    /// Шаблоны запрещённых имён целей: `*.internal`, `169.254.*`
+    pub block_domains: Vec<String>,
+    /// Принимать HTTP CONNECT и на порту SOCKS
+    pub sniff_http: bool,
+    /// Читать адрес клиента из заголовка PROXY protocol от балансировщика
+    pub accept_proxy_protocol: bool,
+    /// TCP keepalive: простой до первой пробы и интервал между пробами, секунды
+    pub keepalive_time: Option<u64>,
+    pub keepalive_intvl: Option<u64>,
+    /// Сколько проб без ответа до разрыва
+    pub keepalive_probes: Option<u32>,
+    /// Сбрасывать соединения после ошибки и ждать столько секунд, пока клиент
+    /// закроет соединение после успешной сессии
+    pub linger_secs: Option<u64>,
+    /// Режим проверки: через сколько мс закрывать соединение после подключения к цели
+    pub test_mode: Option<u64>,
+    /// Правила для адресов назначения, проверяются по порядку
+    pub rules: Vec<AclRule>,
+    /// DNS сервер (IP:порт) для доменов из запросов, опрашивается по TCP
+    pub dns: Option<SocketAddr>,
+    /// Какое семейство адресов домена пробовать первым: auto, ipv4 или ipv6
+    pub prefer: Option<String>,
+    /// Вышестоящий SOCKS5 прокси (IP:порт) для исходящих соединений
+    pub upstream: Option<SocketAddr>,
+    /// Имя и пароль для вышестоящего прокси, задаются вместе
+    pub upstream_user: Option<String>,
+    pub upstream_pass: Option<String>,
+    /// Локальный IP для прямых исходящих соединений
+    pub outbound_ip: Option<IpAddr>,
+    /// Порт дополнительного слушателя HTTP CONNECT на том же адресе, что и SOCKS
+    pub http_proxy_port: Option<u16>,
+    /// Адрес HTTP страницы метрик Prometheus (IP:порт)
+    pub metrics_addr: Option<SocketAddr>,
+    /// Unix сокет со снимком активных соединений в JSON
+    pub stats_socket: Option<PathBuf>,
+    /// Дополнительный слушатель SOCKS на Unix сокете
+    pub unix_socket: Option<PathBuf>,
+    /// Права файла Unix сокета, в файле - `0o660`
+    pub unix_socket_mode: Option<u32>,
+    /// Владелец файла Unix сокета: числовые `uid`, `uid:gid` или `:gid`
+    pub unix_socket_owner: Option<String>,
+    /// Журнал доступа в Common Log Format, дописывается
+    pub access_log: Option<PathBuf>,
+    /// PEM файлы сертификата и ключа; вместе включают SOCKS поверх TLS
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Ключ обфускации потока клиентов SOCKS порта, hex
+    pub obfuscate_key: Option<String>,
+    pub log_level: Option<String>,
+    /// Уйти в фон после запуска (только Unix)
+    pub daemon: bool,
+    /// Файл с PID демона
+    pub pid_file: Option<PathBuf>,
+    /// Вывести итоговые настройки (`--dump-config`) и выйти; только в параметрах
+    #[serde(skip)]
+    pub dump_config: bool,
+}
+
+impl Config {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Config> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read config file {}", path.display()))?;
+        toml::from_str(&text)
+            .with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Применяет параметры командной строки поверх текущих значений.
+    /// Если `--auth`, `--allow`, `--deny`, `--port`, `--proxy-protocol-v2-for` или
+    /// `--block-domain` переданы, они заменяют соответствующие списки из файла,
+    /// а не дополняют их
+    pub fn merge_args(mut self, args: impl IntoIterator<Item = String>) -> Result<Config> {
+        let mut cli_users = false;
+        let mut cli_rules = false;
+        let mut cli_ports = false;
+        let mut cli_proxy_protocol_v2 = false;
+        let mut cli_block_domains = false;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().with_context(|| format!("{} requires a value", name));
+            match arg.as_str() {
+                "--config" => {
+                    // Файл уже прочитан до разбора остальных параметров
+                    value("--config")?;
+                }
+                "--threads" => {
+                    self.threads = Some(value("--threads")?.parse().context("Invalid thread count")?);
+                }
+                "--workers" => {
+                    self.workers = Some(value("--workers")?.parse().context("Invalid worker count")?);
+                }
+                "--auth" => {
+                    let value = value("--auth")?;
+                    let (username, password) = value
+                        .split_once(':')
+                        .context("--auth expects user:pass")?;
+                    if !cli_users {
+                        self.users.clear();
+                        cli_users = true;
+                    }
+                    self.users.push(User { username: username.to_owned(), password: password.to_owned() });
+                }
+                "--auth-methods" => {
+                    let methods = value("--auth-methods")?;
+                    self.auth_methods = Some(methods.split(',').map(|method| method.trim().to_owned()).collect());
+                }
+                "--allow-bind" => self.allow_bind = true,
+                "--bind-timeout" => {
+                    self.bind_timeout = Some(value("--bind-timeout")?
+                        .parse()
+                        .context("Invalid bind timeout (milliseconds)")?);
+                }
+                "--bind-port-range" => self.bind_port_range = Some(value("--bind-port-range")?),
+                "--connect-timeout" => {
+                    self.connect_timeout = Some(value("--connect-timeout")?
+                        .parse()
+                        .context("Invalid connect timeout (milliseconds)")?);
+                }
+                "--handshake-timeout" => {
+                    self.handshake_timeout = Some(value("--handshake-timeout")?
+                        .parse()
+                        .context("Invalid handshake timeout (milliseconds)")?);
+                }
+                "--idle-timeout" => {
+                    self.idle_timeout = Some(value("--idle-timeout")?
+                        .parse()
+                        .context("Invalid idle timeout (milliseconds)")?);
+                }
+                "--rate-limit" => {
+                    self.rate_limit = Some(value("--rate-limit")?
+                        .parse()
+                        .context("Invalid rate limit (bytes per second)")?);
+                }
+                "--buffer-size" => {
+                    self.buffer_size = Some(value("--buffer-size")?
+                        .parse()
+                        .context("Invalid buffer size (bytes)")?);
+                }
+                "--no-nodelay" => self.no_nodelay = true,
+                "--no-udp-source-check" => self.no_udp_source_check = true,
+                "--emit-proxy-protocol" => self.emit_proxy_protocol = true,
+                "--proxy-protocol-v2-for" => {
+                    let rule = value("--proxy-protocol-v2-for")?;
+                    if !cli_proxy_protocol_v2 {
+                        self.proxy_protocol_v2_for.clear();
+                        cli_proxy_protocol_v2 = true;
+                    }
+                    self.proxy_protocol_v2_for.push(rule);
+                }
+                "--strict-handshake" => self.strict_handshake = true,
+                "--require-hostname" => self.require_hostname = true,
+                "--sniff-http" => self.sniff_http = true,
+                "--block-domain" => {
+                    let pattern = value("--block-domain")?;
+                    if !cli_block_domains {
+                        self.block_domains.clear();
+                        cli_block_domains = true;
+                    }
+                    self.block_domains.push(pattern);
+                }
+                "--accept-proxy-protocol" => self.accept_proxy_protocol = true,
+                "--max-connections" => {
+                    self.max_connections = Some(value("--max-connections")?.parse().context("Invalid connection limit")?);
+                }
+                "--max-conn-per-ip" => {
+                    self.max_conn_per_ip = Some(value("--max-conn-per-ip")?.parse().context("Invalid per-IP connection limit")?);
+                }
+                "--udp-peer-timeout" => {
+                    self.udp_peer_timeout = Some(value("--udp-peer-timeout")?.parse().context("Invalid UDP peer timeout (seconds)")?);
+                }
+                "--udp-max-peers" => {
+                    self.udp_max_peers = Some(value("--udp-max-peers")?.parse().context("Invalid UDP peer limit")?);
+                }
+                "--udp-max-datagram" => {
+                    self.udp_max_datagram = Some(value("--udp-max-datagram")?.parse().context("Invalid UDP datagram size (bytes)")?);
+                }
+                "--linger-secs" => {
+                    self.linger_secs = Some(value("--linger-secs")?.parse().context("Invalid linger time (seconds)")?);
+                }
+                "--udp-external-addr" => {
+                    self.udp_external_addr = Some(value("--udp-external-addr")?.parse().context("Invalid UDP external address")?);
+                }
+                "--udp-port-range" => self.udp_port_range = Some(value("--udp-port-range")?),
+                "--keepalive-time" => {
+                    self.keepalive_time = Some(value("--keepalive-time")?.parse().context("Invalid keepalive time (seconds)")?);
+                }
+                "--keepalive-intvl" => {
+                    self.keepalive_intvl = Some(value("--keepalive-intvl")?.parse().context("Invalid keepalive interval (seconds)")?);
+                }
+                "--keepalive-probes" => {
+                    self.keepalive_probes = Some(value("--keepalive-probes")?.parse().context("Invalid keepalive probe count")?);
+                }
+                "--test-mode" => {
+                    self.test_mode = Some(value("--test-mode")?
+                        .parse()
+                        .context("Invalid test mode delay (milliseconds)")?);
+                }
+                "--allow" | "--deny" => {
+                    let rule = value(&arg)?;
+                    if !cli_rules {
+                        self.rules.clear();
+                        cli_rules = true;
+                    }
+                    self.rules.push(if arg == "--allow" { AclRule::Allow(rule) } else { AclRule::Deny(rule) });
+                }
+                "--dns" => {
+                    self.dns = Some(value("--dns")?
+                        .parse()
+                        .context("Invalid --dns address (expected IP:port, e.g. 1.1.1.1:53)")?);
+                }
+                "--prefer" => self.prefer = Some(value("--prefer")?),
+                "--upstream-user" => self.upstream_user = Some(value("--upstream-user")?),
+                "--upstream-pass" => self.upstream_pass = Some(value("--upstream-pass")?),
+                "--upstream" => {
+                    self.upstream = Some(value("--upstream")?
+                        .parse()
+                        .context("Invalid --upstream address (expected IP:port, e.g. 127.0.0.1:9050)")?);
+                }
+                "--outbound-ip" => {
+                    self.outbound_ip = Some(value("--outbound-ip")?.parse().context("Invalid --outbound-ip address")?);
+                }
+                "--http-proxy-port" => {
+                    self.http_proxy_port = Some(value("--http-proxy-port")?.parse().context("Invalid --http-proxy-port port number")?);
+                }
+                "--metrics-addr" => {
+                    self.metrics_addr = Some(value("--metrics-addr")?
+                        .parse()
+                        .context("Invalid --metrics-addr address (expected IP:port, e.g. 127.0.0.1:9100)")?);
+                }
+                "--stats-socket" => self.stats_socket = Some(value("--stats-socket")?.into()),
+                "--daemon" => self.daemon = true,
+                "--foreground" => self.daemon = false,
+                "--dump-config" => self.dump_config = true,
+                "--pid-file" => self.pid_file = Some(value("--pid-file")?.into()),
+                "--unix-socket" => self.unix_socket = Some(value("--unix-socket")?.into()),
+                "--unix-socket-mode" => {
+                    self.unix_socket_mode = Some(u32::from_str_radix(&value("--unix-socket-mode")?, 8)
+                        .context("Invalid --unix-socket-mode (expected octal mode, e.g. 660)")?);
+                }
+                "--unix-socket-owner" => self.unix_socket_owner = Some(value("--unix-socket-owner")?),
+                "--access-log" => self.access_log = Some(value("--access-log")?.into()),
+                "--tls-cert" => self.tls_cert = Some(value("--tls-cert")?.into()),
+                "--obfuscate-key" => self.obfuscate_key = Some(value("--obfuscate-key")?),
+                "--tls-key" => self.tls_key = Some(value("--tls-key")?.into()),
+                "--log-level" => self.log_level = Some(value("--log-level")?),
+                "--bind" => self.bind = Some(value("--bind")?),
+                "--port" => {
+                    let port = value("--port")?.parse().context("Invalid --port port number")?;
+                    if !cli_ports {
+                        self.ports.clear();
+                        cli_ports = true;
+                    }
+                    self.ports.push(PortConfig::new(port));
+                }
+                _ => self.port = Some(arg.parse().context("Invalid port number")?),
+            }
+        }
+        // Проверяется здесь, а не в `server()`: демонизация идёт до сборки серверов
+        ensure!(self.daemon || self.pid_file.is_none(), "--pid-file requires --daemon");
+        Ok(self)
+    }
+
+    pub fn log_level(&self) -> Result<Level> {
+        match &self.log_level {
+            Some(level) => level
+                .parse()
+                .context("Invalid log level (expected error, warn, info, debug or trace)"),
+            None => Ok(Level::INFO),
+        }
+    }
+
+    /// Рантайм tokio с `threads` рабочими потоками (по умолчанию - по числу логических CPU)
+    pub fn runtime(&self) -> Result<Runtime> {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        if let Some(threads) = self.threads {
+            ensure!(threads > 0, "Thread count must be positive");
+            builder.worker_threads(threads);
+        }
+        builder.enable_all().build().context("Cannot start async runtime")
+    }
+
+    /// Сколько серверов запускать на одном порту. SO_REUSEPORT есть только
+    /// на Unix, на остальных платформах всегда запускается один
+    pub fn workers(&self) -> Result<usize> {
+        let workers = self.workers.unwrap_or(1);
+        ensure!(workers > 0, "Worker count must be positive");
+        if cfg!(not(unix)) && workers > 1 {
+            warn!("--workers requires SO_REUSEPORT, which is not supported on this platform; running one worker");
+            return Ok(1);
+        }
+        Ok(workers)
+    }
+
+    /// Лимиты соединений, общие для всех воркеров и портов
+    pub fn connection_limit(&self) -> Result<ConnectionLimit> {
+        let mut limit = ConnectionLimit::default();
+        if let Some(max) = self.max_connections {
+            ensure!(max > 0, "Connection limit must be positive");
+            limit = limit.with_total(max);
+        }
+        if let Some(max) = self.max_conn_per_ip {
+            ensure!(max > 0, "Per-IP connection limit must be positive");
+            limit = limit.with_per_ip(max);
+        }
+        Ok(limit)
+    }
+
+    /// Журнал доступа, общий для всех воркеров
+    pub fn access_logger(&self) -> Result<Option<AccessLogger>> {
+        self.access_log.as_ref().map(AccessLogger::open).transpose()
+    }
+
+    /// Адрес для прослушивания: `bind`, а порт из него или из `port`
+    pub fn bind_addr(&self) -> Result<SocketAddr> {
+        let mut bind_addr = match &self.bind {
+            Some(value) => parse_bind(value)?,
+            None => SocketAddr::from(([0, 0, 0, 0], 0)),
+        };
+        if bind_addr.port() == 0 {
+            bind_addr.set_port(self.port.unwrap_or_else(|| {
+                info!("Port is not passed. Using {}...", DEFAULT_PORT);
+                DEFAULT_PORT
+            }));
+        }
+        Ok(bind_addr)
+    }
+
+    /// Настройки каждого порта прослушивания: без `ports` это один порт из
+    /// `bind_addr`, иначе порт из `port` (если задан) и все `ports`. Адрес
+    /// в них задан полностью. Слушатель HTTP, страницу метрик и сокет
+    /// статистики обслуживает только первый порт
+    pub fn listeners(&self) -> Result<Vec<Config>> {
+        let mut listener = self.clone();
+        listener.ports.clear();
+        if self.ports.is_empty() {
+            listener.bind = Some(self.bind_addr()?.to_string());
+            return Ok(vec![listener]);
+        }
+        let ip = match &self.bind {
+            Some(value) => {
+                let addr = parse_bind(value)?;
+                ensure!(addr.port() == 0, "--port cannot be combined with a port in --bind");
+                addr.ip()
+            }
+            None => IpAddr::from([0, 0, 0, 0]),
+        };
+        let ports: Vec<PortConfig> = self.port.map(PortConfig::new).into_iter().chain(self.ports.iter().cloned()).collect();
+        let mut listeners = Vec::new();
+        for (i, port) in ports.iter().enumerate() {
+            ensure!(port.port != 0, "Port must be positive");
+            ensure!(
+                !ports[..i].iter().any(|other| other.port == port.port),
+                "Port {} is listed twice",
+                port.port
+            );
+            ensure!(self.http_proxy_port != Some(port.port), "--http-proxy-port must differ from the SOCKS ports");
+            let mut listener = listener.clone();
+            listener.bind = Some(SocketAddr::new(ip, port.port).to_string());
+            if let Some(users) = &port.users {
+                listener.users = users.clone();
+            }
+            if let Some(methods) = &port.auth_methods {
+                listener.auth_methods = Some(methods.clone());
+            }
+            if let Some(rules) = &port.rules {
+                listener.rules = rules.clone();
+            }
+            if i > 0 {
+                listener.http_proxy_port = None;
+                listener.metrics_addr = None;
+                listener.stats_socket = None;
+                listener.unix_socket = None;
+            }
+            listeners.push(listener);
+        }
+        Ok(listeners)
+    }
+
+    /// Итоговые настройки в формате файла для `--dump-config`. Значения
+    /// проверяются так же, как при запуске; вместо пропущенных таймаутов,
+    /// размеров и уровня логов подставляются значения по умолчанию, диапазоны
+    /// портов приводятся к виду `first-last`
+    pub fn dump(&self) -> Result<String> {
+        for listener in self.listeners()? {
+            listener.server()?;
+        }
+        let mut effective = self.clone();
+        if self.ports.is_empty() {
+            effective.port = Some(self.bind_addr()?.port());
+        }
+        effective.bind_timeout.get_or_insert(DEFAULT_ACCEPT_TIMEOUT.as_millis() as u64);
+        effective.handshake_timeout.get_or_insert(DEFAULT_HANDSHAKE_TIMEOUT.as_millis() as u64);
+        effective.buffer_size.get_or_insert(DEFAULT_BUFFER_SIZE);
+        effective.udp_peer_timeout.get_or_insert(udp::DEFAULT_PEER_TIMEOUT.as_secs());
+        effective.udp_max_peers.get_or_insert(udp::DEFAULT_MAX_PEERS);
+        effective.udp_max_datagram.get_or_insert(udp::MAX_DATAGRAM_SIZE);
+        effective.bind_port_range = normalize_range(self.bind_port_range.as_deref())?;
+        effective.udp_port_range = normalize_range(self.udp_port_range.as_deref())?;
+        effective.log_level = Some(self.log_level()?.as_str().to_lowercase());
+        toml::to_string(&effective).context("Cannot format config")
+    }
+
+    /// Проверяет значения и собирает по ним сервер
+    pub fn server(&self) -> Result<SocksServer> {
+        let bind_addr = self.bind_addr()?;
+        let mut server = SocksServer::new(bind_addr)
+            .with_reuse_port(self.workers()? > 1);
+        if let Some(port) = self.http_proxy_port {
+            ensure!(port != bind_addr.port(), "--http-proxy-port must differ from the SOCKS port");
+            server = server.with_http_proxy(SocketAddr::new(bind_addr.ip(), port));
+        }
+        if let Some(ms) = self.connect_timeout {
+            ensure!(ms > 0, "Connect timeout must be positive");
+            server = server.with_connect_timeout(Duration::from_millis(ms));
+        }
+        if let Some(ms) = self.handshake_timeout {
+            ensure!(ms > 0, "Handshake timeout must be positive");
+            server = server.with_handshake_timeout(Duration::from_millis(ms));
+        }
+        if let Some(ms) = self.idle_timeout {
+            ensure!(ms > 0, "Idle timeout must be positive");
+            server = server.with_idle_timeout(Duration::from_millis(ms));
+        }
+        if let Some(rate) = self.rate_limit {
+            ensure!(rate > 0, "Rate limit must be positive");
+            server = server.with_rate_limit(rate);
+        }
+        if let Some(size) = self.buffer_size {
+            ensure!(
+                size.is_power_of_two() && BUFFER_SIZE_RANGE.contains(&size),
+                "Buffer size must be a power of two between 512 and 65536, e.g. 65536 (got {})",
+                size
+            );
+            server = server.with_buffer_size(size);
+        }
+        server = server
+            .with_nodelay(!self.no_nodelay)
+            .with_udp_source_check(!self.no_udp_source_check)
+            .with_emit_proxy_protocol(self.emit_proxy_protocol)
+            .with_strict_handshake(self.strict_handshake)
+            .with_require_hostname(self.require_hostname)
+            .with_http_sniffing(self.sniff_http)
+            .with_accept_proxy_protocol(self.accept_proxy_protocol);
+        let mut udp_options = udp::UdpOptions::default();
+        if let Some(secs) = self.udp_peer_timeout {
+            ensure!(secs > 0, "UDP peer timeout must be positive");
+            udp_options.peer_timeout = Duration::from_secs(secs);
+        }
+        if let Some(max) = self.udp_max_peers {
+            ensure!(max > 0, "UDP peer limit must be positive");
+            udp_options.max_peers = max;
+        }
+        if let Some(size) = self.udp_max_datagram {
+            ensure!(
+                (udp::MIN_DATAGRAM_SIZE..=udp::MAX_DATAGRAM_SIZE).contains(&size),
+                "UDP datagram size must be between {} and {} bytes (got {})",
+                udp::MIN_DATAGRAM_SIZE,
+                udp::MAX_DATAGRAM_SIZE,
+                size
+            );
+            udp_options.max_datagram_size = size;
+        }
+        udp_options.external_ip = self.udp_external_addr;
+        if let Some(range) = &self.udp_port_range {
+            udp_options.ports = Some(range.parse()?);
+        }
+        server = server.with_udp_options(udp_options);
+        if let Some(keepalive) = self.keepalive()? {
+            server = server.with_keepalive(keepalive);
+        }
+        if let Some(secs) = self.linger_secs {
+            server = server.with_linger(Duration::from_secs(secs));
+        }
+        if let Some(ms) = self.test_mode {
+            server = server.with_test_mode(Duration::from_millis(ms));
+        }
+
+        let mut acl = AccessControl::new();
+        for rule in &self.rules {
+            let result = match rule {
+                AclRule::Allow(rule) => acl.allow(rule),
+                AclRule::Deny(rule) => acl.deny(rule),
+            };
+            result.context("Invalid access rule (expected CIDR, e.g. 10.0.0.0/8 or 10.0.0.0/8:443)")?;
+        }
+        server = server.with_bind(self.allow_bind).with_access_control(acl);
+        let mut proxy_protocol_v2_targets = TargetList::new();
+        for rule in &self.proxy_protocol_v2_for {
+            proxy_protocol_v2_targets
+                .add(rule)
+                .context("Invalid --proxy-protocol-v2-for rule (expected CIDR, e.g. 10.0.0.0/8 or 10.0.0.5:5432)")?;
+        }
+        server = server.with_proxy_protocol_v2_targets(proxy_protocol_v2_targets);
+        let mut blocked_domains = DomainMatcher::new();
+        for pattern in &self.block_domains {
+            blocked_domains.add(pattern).context("Invalid --block-domain pattern")?;
+        }
+        server = server.with_blocked_domains(blocked_domains);
+        if let Some(ms) = self.bind_timeout {
+            ensure!(ms > 0, "Bind timeout must be positive");
+            server = server.with_bind_timeout(Duration::from_millis(ms));
+        }
+        if let Some(range) = &self.bind_port_range {
+            server = server.with_bind_port_range(range.parse()?);
+        }
+        if let Some(dns) = self.dns {
+            server = server.with_dns(dns);
+        }
+        if let Some(prefer) = &self.prefer {
+            server = server.with_address_preference(prefer.parse()?);
+        }
+        if let Some(upstream) = self.upstream {
+            server = server.with_upstream(upstream);
+        }
+        match (&self.upstream_user, &self.upstream_pass) {
+            (Some(user), Some(pass)) => {
+                ensure!(self.upstream.is_some(), "--upstream-user and --upstream-pass require --upstream");
+                ensure!(
+                    (1..=255).contains(&user.len()) && (1..=255).contains(&pass.len()),
+                    "Upstream username and password must be 1 to 255 bytes long"
+                );
+                server = server.with_upstream_auth(user.clone(), pass.clone());
+            }
+            (None, None) => {}
+            _ => bail!("--upstream-user and --upstream-pass must be given together"),
+        }
+        if let Some(ip) = self.outbound_ip {
+            server = server.with_outbound_ip(ip);
+        }
+        if let Some(addr) = self.metrics_addr {
+            server = server.with_metrics_addr(addr);
+        }
+        if let Some(path) = &self.stats_socket {
+            ensure!(cfg!(unix), "--stats-socket is only supported on Unix");
+            server = server.with_stats_socket(path);
+        }
+        if let Some(path) = &self.unix_socket {
+            ensure!(cfg!(unix), "--unix-socket is only supported on Unix");
+            ensure!(self.unix_socket_mode.is_none_or(|mode| mode <= 0o7777), "Invalid --unix-socket-mode (expected octal mode, e.g. 660)");
+            let (uid, gid) = match &self.unix_socket_owner {
+                Some(owner) => parse_owner(owner)?,
+                None => (None, None),
+            };
+            server = server.with_unix_socket(path).with_unix_socket_permissions(self.unix_socket_mode, uid, gid);
+        }
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => server = server.with_tls(tls::load_server_config(cert, key)?),
+            (None, None) => {}
+            _ => bail!("--tls-cert and --tls-key must be given together"),
+        }
+        if let Some(key) = &self.obfuscate_key {
+            ensure!(self.tls_cert.is_none(), "--obfuscate-key cannot be combined with TLS");
+            server = server.with_obfuscation(key.parse()?);
+        }
+
+        for user in &self.users {
+            ensure!(
+                (1..=255).contains(&user.username.len()) && (1..=255).contains(&user.password.len()),
+                "Username and password must be 1 to 255 bytes long"
+            );
+            server = server.with_auth(user.username.clone(), user.password.clone());
+        }
+        if let Some(methods) = &self.auth_methods {
+            server = server.with_auth_priority(self.auth_priority(methods)?);
+        }
+        Ok(server)
+    }
+
+    /// Параметры keepalive; интервал и число проб имеют смысл только вместе со временем
+    fn keepalive(&self) -> Result<Option<Keepalive>> {
+        let Some(time) = self.keepalive_time else {
+            ensure!(
+                self.keepalive_intvl.is_none() && self.keepalive_probes.is_none(),
+                "--keepalive-intvl and --keepalive-probes require --keepalive-time"
+            );
+            return Ok(None);
+        };
+        ensure!(time > 0, "Keepalive time must be positive");
+        let mut keepalive = Keepalive::new(Duration::from_secs(time));
+        if let Some(interval) = self.keepalive_intvl {
+            ensure!(interval > 0, "Keepalive interval must be positive");
+            keepalive = keepalive.with_interval(Duration::from_secs(interval));
+        }
+        if let Some(probes) = self.keepalive_probes {
+            ensure!(probes > 0, "Keepalive probe count must be positive");
+            keepalive = keepalive.with_retries(probes);
+        }
+        Ok(Some(keepalive))
+    }
+
+    /// Коды методов аутентификации по именам из `auth_methods`
+    fn auth_priority(&self, methods: &[String]) -> Result<Vec<u8>> {
+        ensure!(!methods.is_empty(), "At least one auth method must be enabled");
+        let mut priority = Vec::new();
+        for method in methods {
+            let id = match method.as_str() {
+                "none" => METHOD_NO_AUTH,
+                "password" => {
+                    ensure!(!self.users.is_empty(), "Auth method \"password\" requires at least one user (--auth)");
+                    METHOD_USERNAME_PASSWORD
+                }
+                _ => bail!("Unknown auth method {:?} (expected none or password)", method),
+            };
+            ensure!(!priority.contains(&id), "Auth method {:?} is listed twice", method);
+            priority.push(id);
+        }
+        Ok(priority)
+    }
+}
+
+
+/// Диапазон портов в виде `first-last`, как его понимает сервер
+fn normalize_range(range: Option<&str>) -> Result<Option<String>> {
+    Ok(range.map(str::parse::<PortRange>).transpose()?.map(|range| range.to_string()))
+}
+
+/// Принимает как полный адрес, так и один IP (тогда порт берётся отдельно)
+fn parse_bind(value: &str) -> Result<SocketAddr> {
+    match value.parse::<SocketAddr>() {
+        Ok(addr) => Ok(addr),
+        Err(_) => match value.parse::<IpAddr>() {
+            Ok(ip) => Ok(SocketAddr::new(ip, 0)),
+            Err(_) => bail!(
+                "Invalid bind address {:?}: expected IP or IP:port, e.g. 127.0.0.1, 127.0.0.1:1080 or [::1]:1080",
+                value
+            ),
+        },
+    }
+}
+
+/// `uid`, `uid:gid` или `:gid`, числами: имена пользователей без libc не разрешить
+fn parse_owner(value: &str) -> Result<(Option<u32>, Option<u32>)> {
+    let (uid, gid) = value.split_once(':').unwrap_or((value, ""));
+    let parse = |id: &str| -> Result<Option<u32>> {
+        if id.is_empty() {
+            return Ok(None);
+        }
+        match id.parse() {
+            Ok(id) => Ok(Some(id)),
+            Err(_) => bail!("Invalid --unix-socket-owner {:?}: expected numeric uid[:gid], e.g. 1000:1000", value),
+        }
+    };
+    let owner = (parse(uid)?, parse(gid)?);
+    ensure!(owner != (None, None), "Invalid --unix-socket-owner {:?}: expected numeric uid[:gid], e.g. 1000:1000", value);
+    Ok(owner)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn dump_shows_effective_bind_settings() {
+        let config = Config::default().merge_args(args(&["1080", "--allow-bind", "--bind-port-range", " 40000 - 40001 "])).unwrap();
+        let dump: toml::Table = toml::from_str(&config.dump().unwrap()).unwrap();
+        assert_eq!(dump["port"].as_integer(), Some(1080));
+        assert_eq!(dump["bind_port_range"].as_str(), Some("40000-40001"));
+        assert_eq!(dump["bind_timeout"].as_integer(), Some(DEFAULT_ACCEPT_TIMEOUT.as_millis() as i64));
+
+        let config = Config::default().merge_args(args(&["--bind-timeout", "1500", "--bind-port-range", "40000"])).unwrap();
+        let dump: toml::Table = toml::from_str(&config.dump().unwrap()).unwrap();
+        assert_eq!(dump["bind_timeout"].as_integer(), Some(1500));
+        assert_eq!(dump["bind_port_range"].as_str(), Some("40000-40000"));
+    }
+
+    #[test]
+    fn dump_can_be_loaded_back() {
+        let config = Config::default()
+            .merge_args(args(&["1080", "--port", "1081", "--auth", "user:pass", "--deny", "10.0.0.0/8", "--udp-port-range", "5000-5001"]))
+            .unwrap();
+        let dump = config.dump().unwrap();
+        let loaded: Config = toml::from_str(&dump).unwrap();
+        assert_eq!(loaded.dump().unwrap(), dump);
+    }
+
+    #[test]
+    fn dump_rejects_invalid_values() {
+        let config = Config::default().merge_args(args(&["--bind-port-range", "5-1", "--dump-config"])).unwrap();
+        assert!(config.dump_config);
+        assert!(config.dump().is_err());
+        let config = Config::default().merge_args(args(&["--bind-timeout", "0"])).unwrap();
+        assert!(config.dump().is_err());
+    }
+}
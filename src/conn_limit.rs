@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, warn};
+use crate::canonical_ip;
+
+
+/// Ограничение числа одновременных соединений: всего и с одного IP клиента.
+/// Клонируется в каждый воркер, счётчики общие
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionLimit {
+    /// Без него соединения с одного IP не ограничиваются
+    per_ip: Option<u32>,
+    /// Без него общее число соединений не ограничивается
+    total: Option<u32>,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    per_ip: HashMap<IpAddr, u32>,
+    total: u32,
+    /// Общий лимит исчерпан; предупреждение пишется только при переходе
+    saturated: bool,
+}
+
+impl ConnectionLimit {
+    pub fn with_per_ip(mut self, max: u32) -> ConnectionLimit {
+        assert!(max > 0, "Connection limit must be positive");
+        self.per_ip = Some(max);
+        self
+    }
+
+    pub fn with_total(mut self, max: u32) -> ConnectionLimit {
+        assert!(max > 0, "Connection limit must be positive");
+        self.total = Some(max);
+        self
+    }
+
+    /// Занимает место для соединения с `ip`; оно освобождается, когда
+    /// возвращённое место будет удалено. `None`, если лимит исчерпан
+    pub fn acquire(&self, ip: IpAddr) -> Option<ConnectionSlot> {
+        if self.per_ip.is_none() && self.total.is_none() {
+            return Some(ConnectionSlot { limit: None, ip });
+        }
+        let ip = canonical_ip(ip);
+        let mut state = self.lock();
+        if let Some(max) = self.total {
+            if state.total >= max {
+                if !state.saturated {
+                    state.saturated = true;
+                    warn!("connection limit of {} reached, refusing new connections", max);
+                } else {
+                    debug!("connection limit reached, refusing {}", ip);
+                }
+                return None;
+            }
+        }
+        if let Some(max) = self.per_ip {
+            let count = state.per_ip.entry(ip).or_insert(0);
+            if *count >= max {
+                warn!("too many connections from {}", ip);
+                return None;
+            }
+            *count += 1;
+        }
+        state.total += 1;
+        Some(ConnectionSlot { limit: Some(self.clone()), ip })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+
+/// Место соединения в `ConnectionLimit`
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    limit: Option<ConnectionLimit>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let Some(limit) = &self.limit else {
+            return;
+        };
+        let mut state = limit.lock();
+        state.total -= 1;
+        state.saturated = false;
+        if let Some(count) = state.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            // Записи отключившихся клиентов не копятся
+            if *count == 0 {
+                state.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_ip_limit() {
+        let limit = ConnectionLimit::default().with_per_ip(2);
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let first = limit.acquire(client).unwrap();
+        let _second = limit.acquire(client).unwrap();
+        assert!(limit.acquire(client).is_none());
+        // Другие клиенты не затронуты, IPv4-mapped адрес считается тем же клиентом
+        assert!(limit.acquire("192.0.2.2".parse().unwrap()).is_some());
+        assert!(limit.acquire("::ffff:192.0.2.1".parse().unwrap()).is_none());
+        drop(first);
+        assert!(limit.acquire(client).is_some());
+        assert_eq!(limit.lock().per_ip.len(), 1);
+    }
+
+    #[test]
+    fn total_limit() {
+        let limit = ConnectionLimit::default().with_total(1);
+        let slot = limit.acquire("192.0.2.1".parse().unwrap()).unwrap();
+        assert!(limit.acquire("192.0.2.2".parse().unwrap()).is_none());
+        drop(slot);
+        assert!(limit.acquire("192.0.2.2".parse().unwrap()).is_some());
+    }
+
+    #[test]
+    fn unlimited() {
+        let limit = ConnectionLimit::default();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let slots: Vec<_> = (0..100).map(|_| limit.acquire(client).unwrap()).collect();
+        assert_eq!(slots.len(), 100);
+    }
+}
@@ -0,0 +1,89 @@
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+
+
+/// Процесс-демон после `daemonize`: родитель ждёт `ready`, чтобы завершиться
+/// только когда сервер уже слушает порты
+pub struct Daemon {
+    #[cfg(unix)]
+    ready: std::fs::File,
+    pid_file: Option<PathBuf>,
+}
+
+impl Daemon {
+    /// Сообщает родителю, что запуск удался, и отвязывает stdout и stderr от
+    /// терминала. До этого ошибки запуска ещё видны пользователю
+    pub fn ready(&self) -> Result<()> {
+        #[cfg(unix)]
+        {
+            use std::io::Write;
+
+            let null = std::fs::OpenOptions::new().write(true).open("/dev/null")?;
+            nix::unistd::dup2_stdout(&null)?;
+            nix::unistd::dup2_stderr(&null)?;
+            (&self.ready).write_all(&[0])?;
+        }
+        Ok(())
+    }
+}
+
+/// Файл PID удаляется при остановке сервера
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        if let Some(path) = &self.pid_file {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+
+/// Уходит в фон: fork, setsid, stdin из /dev/null, файл PID. Возвращается
+/// только в дочернем процессе; родитель ждёт `Daemon::ready` и завершается
+/// с кодом 0, а если потомок умер раньше - с кодом 1. Вызывать до запуска
+/// любых потоков: после fork в потомке остаётся только вызвавший поток.
+/// Рабочий каталог не меняется, чтобы относительные пути из настроек работали
+#[cfg(unix)]
+pub fn daemonize(pid_file: Option<&Path>) -> Result<Daemon> {
+    use std::io::Read;
+    use anyhow::Context;
+    use nix::unistd::{fork, pipe, setsid, ForkResult};
+
+    let (read_end, write_end) = pipe()?;
+    // SAFETY: потоков ещё нет, так что в потомке нечему остаться заблокированным
+    match unsafe { fork() }? {
+        ForkResult::Parent { child } => {
+            drop(write_end);
+            let mut status = [0];
+            let ready = std::fs::File::from(read_end).read(&mut status).unwrap_or(0) == 1;
+            if !ready {
+                let _ = nix::sys::wait::waitpid(child, None);
+                eprintln!("Server failed to start");
+                std::process::exit(1);
+            }
+            std::process::exit(0);
+        }
+        ForkResult::Child => {
+            drop(read_end);
+            setsid()?;
+            let null = std::fs::File::open("/dev/null")?;
+            nix::unistd::dup2_stdin(&null)?;
+            let pid_file = pid_file.map(Path::to_path_buf);
+            if let Some(path) = &pid_file {
+                std::fs::write(path, format!("{}\n", std::process::id()))
+                    .with_context(|| format!("Cannot write PID file {}", path.display()))?;
+            }
+            Ok(Daemon { ready: std::fs::File::from(write_end), pid_file })
+        }
+    }
+}
+
+/// Вне Unix уходить в фон нечем: сервер остаётся на переднем плане
+#[cfg(not(unix))]
+pub fn daemonize(pid_file: Option<&Path>) -> Result<Daemon> {
+    eprintln!("--daemon is not supported on this platform, running in the foreground");
+    let pid_file = pid_file.map(Path::to_path_buf);
+    if let Some(path) = &pid_file {
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+    }
+    Ok(Daemon { pid_file })
+}
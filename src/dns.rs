@@ -0,0 +1,362 @@
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::time::Duration;
+use anyhow::{bail, ensure, Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::debug;
+use crate::SocksError;
+
+
+/// Сколько ждать ответа DNS сервера
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+/// Флаг RD: просим сервер выполнить рекурсивный поиск
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const FLAG_RESPONSE: u16 = 0x8000;
+const RCODE_NXDOMAIN: u16 = 3;
+
+
+/// Резолвер, отправляющий запросы по TCP на заданный DNS сервер в обход
+/// системного. Позволяет не раскрывать запрошенные клиентами домены
+/// локальному резолверу и использовать отдельный сервер для split-horizon
+#[derive(Debug, Clone)]
+pub struct DnsResolver {
+    server: SocketAddr,
+    timeout: Duration,
+}
+
+impl DnsResolver {
+    pub fn new(server: SocketAddr) -> DnsResolver {
+        DnsResolver { server, timeout: DEFAULT_TIMEOUT }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> DnsResolver {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Адреса домена: сначала A записи, за ними AAAA. Оба запроса идут
+    /// параллельно; ошибка возвращается, только если не удались оба.
+    /// Имя передаётся серверу байт в байт, UTF-8 не требуется
+    pub async fn lookup(&self, domain: impl AsRef<[u8]>) -> Result<Vec<IpAddr>> {
+        let domain = domain.as_ref();
+        let (v4, v6) = tokio::join!(self.query(domain, TYPE_A), self.query(domain, TYPE_AAAA));
+        match (v4, v6) {
+            (Ok(mut v4), Ok(v6)) => {
+                v4.extend(v6);
+                Ok(v4)
+            }
+            (Ok(addrs), Err(e)) | (Err(e), Ok(addrs)) => {
+                debug!("partial DNS failure for {}: {}", String::from_utf8_lossy(domain), e);
+                Ok(addrs)
+            }
+            (Err(e), Err(_)) => Err(e),
+        }
+    }
+
+    /// Весь обмен с сервером, включая подключение, ограничен `timeout`
+    async fn query(&self, domain: &[u8], qtype: u16) -> Result<Vec<IpAddr>> {
+        let id: u16 = rand::random();
+        let message = encode_query(id, domain, qtype)?;
+
+        let exchange = async {
+            let mut stream = TcpStream::connect(self.server).await?;
+
+            // По TCP сообщение предваряется двухбайтной длиной (RFC 1035, 4.2.2)
+            let mut packet = (message.len() as u16).to_be_bytes().to_vec();
+            packet.extend_from_slice(&message);
+            stream.write_all(&packet).await?;
+
+            let mut len_buf = [0; 2];
+            stream.read_exact(&mut len_buf).await?;
+            let mut response = vec![0; u16::from_be_bytes(len_buf) as usize];
+            stream.read_exact(&mut response).await?;
+            Ok::<_, std::io::Error>(response)
+        };
+        let response = timeout(self.timeout, exchange)
+            .await
+            .context("DNS server did not respond in time")??;
+        parse_response(id, &response)
+    }
+}
+
+
+/// Какое семейство адресов пробовать первым, если домен резолвится в оба
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressPreference {
+    /// Порядок резолвера
+    #[default]
+    Auto,
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressPreference {
+    /// Переставляет адреса предпочтительного семейства в начало, сохраняя
+    /// порядок внутри каждого семейства
+    pub fn order(&self, addrs: &mut [SocketAddr]) {
+        match self {
+            AddressPreference::Auto => {}
+            AddressPreference::Ipv4 => addrs.sort_by_key(|addr| addr.is_ipv6()),
+            AddressPreference::Ipv6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+        }
+    }
+}
+
+impl FromStr for AddressPreference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<AddressPreference> {
+        match s {
+            "auto" => Ok(AddressPreference::Auto),
+            "ipv4" => Ok(AddressPreference::Ipv4),
+            "ipv6" => Ok(AddressPreference::Ipv6),
+            _ => bail!("Invalid address preference {:?} (expected auto, ipv4 or ipv6)", s),
+        }
+    }
+}
+
+
+/// Первый адрес домена, см. `resolve_all`
+pub async fn resolve(domain: &[u8], port: u16, dns: Option<&DnsResolver>) -> Result<SocketAddr> {
+    Ok(resolve_all(domain, port, dns).await?[0])
+}
+
+/// Все адреса домена в порядке резолвера (не пустой список). Резолвит через `dns`,
+/// если он задан, иначе через системный резолвер. Домен - байты из запроса как есть:
+/// клиенты присылают и имена не в UTF-8. Ошибка резолвера превращается в
+/// `SocksError::ResolutionFailed`, пустой ответ - в `SocksError::NoAddresses`
+pub async fn resolve_all(domain: &[u8], port: u16, dns: Option<&DnsResolver>) -> Result<Vec<SocketAddr>> {
+    let text = std::str::from_utf8(domain);
+    // IP в виде строки не нужно отправлять DNS серверу
+    if let Some(ip) = text.ok().and_then(|text| text.parse::<IpAddr>().ok()) {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    // Только для логов
+    let domain_name = String::from_utf8_lossy(domain);
+    let resolved = match (dns, text) {
+        (Some(dns), _) => dns
+            .lookup(domain)
+            .await
+            .map(|ips| ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect::<Vec<_>>()),
+        (None, Ok(text)) => tokio::net::lookup_host((text, port))
+            .await
+            .map(|addrs| addrs.collect())
+            .map_err(Into::into),
+        // Системный резолвер принимает только строки
+        (None, Err(e)) => Err(e.into()),
+    };
+    match resolved {
+        Ok(addrs) if !addrs.is_empty() => Ok(addrs),
+        Ok(_) => {
+            debug!("{} has no addresses", domain_name);
+            Err(SocksError::NoAddresses.into())
+        }
+        Err(e) => {
+            debug!("resolving {} failed: {}", domain_name, e);
+            Err(SocksError::ResolutionFailed.into())
+        }
+    }
+}
+
+
+fn encode_query(id: u16, domain: &[u8], qtype: u16) -> Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(18 + domain.len());
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // QDCOUNT=1, ANCOUNT, NSCOUNT, ARCOUNT=0
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    let name = domain.strip_suffix(b".").unwrap_or(domain);
+    for label in name.split(|&byte| byte == b'.') {
+        ensure!(
+            !label.is_empty() && label.len() <= 63,
+            "Invalid domain name {:?}",
+            String::from_utf8_lossy(domain)
+        );
+        message.push(label.len() as u8);
+        message.extend_from_slice(label);
+    }
+    message.push(0);
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+fn read_u16(message: &[u8], pos: usize) -> Result<u16> {
+    match message.get(pos..pos + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => bail!("DNS response is truncated"),
+    }
+}
+
+/// Пропускает имя (метки или ссылку-сжатие) и возвращает позицию за ним
+fn skip_name(message: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        let len = *message.get(pos).context("DNS response is truncated")?;
+        match len {
+            0 => return Ok(pos + 1),
+            len if len & 0xC0 == 0xC0 => return Ok(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+/// Достаёт из ответа A и AAAA записи; CNAME и прочие записи пропускаются,
+/// рекурсивный сервер сам кладёт в ответ записи для канонического имени
+fn parse_response(id: u16, message: &[u8]) -> Result<Vec<IpAddr>> {
+    ensure!(message.len() >= 12, "DNS response is truncated");
+    ensure!(read_u16(message, 0)? == id, "DNS response id does not match the query");
+    let flags = read_u16(message, 2)?;
+    ensure!(flags & FLAG_RESPONSE != 0, "DNS server sent a query instead of a response");
+    match flags & 0x000F {
+        0 => {}
+        RCODE_NXDOMAIN => bail!("Domain name does not exist"),
+        rcode => bail!("DNS server returned error code {}", rcode),
+    }
+
+    let questions = read_u16(message, 4)?;
+    let answers = read_u16(message, 6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        // За именем идут QTYPE и QCLASS
+        pos = skip_name(message, pos)? + 4;
+    }
+
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(message, pos)?;
+        // TYPE, CLASS, TTL(4), RDLENGTH, затем RDATA
+        let rtype = read_u16(message, pos)?;
+        let rdlength = read_u16(message, pos + 8)? as usize;
+        let rdata = message
+            .get(pos + 10..pos + 10 + rdlength)
+            .context("DNS response is truncated")?;
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) => addrs.push(IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))),
+            (TYPE_AAAA, 16) => {
+                let mut ip = [0; 16];
+                ip.copy_from_slice(rdata);
+                addrs.push(IpAddr::V6(Ipv6Addr::from(ip)));
+            }
+            _ => {}
+        }
+        pos += 10 + rdlength;
+    }
+    Ok(addrs)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: u16 = 0x1234;
+
+    /// Ответ на запрос A для example.com с записями `answers` (уже в формате сети)
+    fn response(id: u16, rcode: u16, answers: &[&[u8]]) -> Vec<u8> {
+        let mut message = encode_query(id, b"example.com", TYPE_A).unwrap();
+        let flags = FLAG_RESPONSE | FLAG_RECURSION_DESIRED | rcode;
+        message[2..4].copy_from_slice(&flags.to_be_bytes());
+        message[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        for answer in answers {
+            message.extend_from_slice(answer);
+        }
+        message
+    }
+
+    /// Запись с именем `name` (метки или ссылка) и данными `rdata`
+    fn record(name: &[u8], rtype: u16, rdata: &[u8]) -> Vec<u8> {
+        let mut record = name.to_vec();
+        record.extend_from_slice(&rtype.to_be_bytes());
+        record.extend_from_slice(&CLASS_IN.to_be_bytes());
+        record.extend_from_slice(&300u32.to_be_bytes());
+        record.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        record.extend_from_slice(rdata);
+        record
+    }
+
+    #[test]
+    fn query_layout() {
+        let query = encode_query(ID, b"www.example.com.", TYPE_AAAA).unwrap();
+        let mut expected = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(b"\x03www\x07example\x03com\x00");
+        expected.extend_from_slice(&[0, 28, 0, 1]);
+        assert_eq!(query, expected);
+    }
+
+    #[test]
+    fn query_invalid_labels() {
+        let long = [b'a'; 64];
+        assert!(encode_query(ID, &long, TYPE_A).is_err());
+        assert!(encode_query(ID, &[b'a'; 63], TYPE_A).is_ok());
+        assert!(encode_query(ID, b"a..b", TYPE_A).is_err());
+        assert!(encode_query(ID, b"", TYPE_A).is_err());
+    }
+
+    #[test]
+    fn answers_with_compression() {
+        // 0xC00C - ссылка на имя в вопросе, по смещению 12
+        let message = response(ID, 0, &[
+            &record(&[0xC0, 0x0C], 5, b"\x03cdn\xC0\x0C"),
+            &record(&[0xC0, 0x0C], TYPE_A, &[192, 0, 2, 1]),
+            &record(b"\x03cdn\xC0\x0C", TYPE_AAAA, &Ipv6Addr::LOCALHOST.octets()),
+        ]);
+        let addrs = parse_response(ID, &message).unwrap();
+        assert_eq!(addrs, [IpAddr::from([192, 0, 2, 1]), IpAddr::V6(Ipv6Addr::LOCALHOST)]);
+    }
+
+    #[test]
+    fn pointer_loop_terminates() {
+        // Ссылка сама на себя: имя не разворачивается, поэтому зацикливаться нечему
+        let mut message = response(ID, 0, &[]);
+        let pos = message.len() as u16;
+        message[6..8].copy_from_slice(&1u16.to_be_bytes());
+        message.extend_from_slice(&record(&(0xC000 | pos).to_be_bytes(), TYPE_A, &[192, 0, 2, 1]));
+        assert_eq!(parse_response(ID, &message).unwrap(), [IpAddr::from([192, 0, 2, 1])]);
+    }
+
+    #[test]
+    fn truncated_answer() {
+        let full = response(ID, 0, &[&record(&[0xC0, 0x0C], TYPE_A, &[192, 0, 2, 1])]);
+        for len in [11, full.len() - 1, full.len() - 5, full.len() - 14] {
+            assert!(parse_response(ID, &full[..len]).is_err(), "length {}", len);
+        }
+        // Заявлена запись, которой нет
+        let mut missing = response(ID, 0, &[]);
+        missing[6..8].copy_from_slice(&1u16.to_be_bytes());
+        assert!(parse_response(ID, &missing).is_err());
+    }
+
+    #[test]
+    fn id_mismatch() {
+        let message = response(ID, 0, &[&record(&[0xC0, 0x0C], TYPE_A, &[192, 0, 2, 1])]);
+        let e = parse_response(ID + 1, &message).unwrap_err();
+        assert!(e.to_string().contains("id does not match"));
+    }
+
+    #[test]
+    fn error_rcode() {
+        let e = parse_response(ID, &response(ID, RCODE_NXDOMAIN, &[])).unwrap_err();
+        assert_eq!(e.to_string(), "Domain name does not exist");
+        let e = parse_response(ID, &response(ID, 2, &[])).unwrap_err();
+        assert_eq!(e.to_string(), "DNS server returned error code 2");
+    }
+
+    #[test]
+    fn not_a_response() {
+        let query = encode_query(ID, b"example.com", TYPE_A).unwrap();
+        assert!(parse_response(ID, &query).is_err());
+    }
+
+    #[test]
+    fn empty_answer() {
+        assert!(parse_response(ID, &response(ID, 0, &[])).unwrap().is_empty());
+    }
+}
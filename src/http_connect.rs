@@ -0,0 +1,245 @@
+use std::io;
+use anyhow::Result;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, info, warn};
+use crate::stats::ConnectionStats;
+use crate::stream::ClientStream;
+use crate::parse::Address;
+use crate::{address_family, check_blocked, connect_any, is_ip_literal, dns, error_reply, relay_target, Config, Command, Request, SocksError, SOCKSReply};
+
+
+/// Максимальный размер строки запроса вместе с заголовками
+const MAX_HEAD_LEN: usize = 8192;
+
+
+/// Статусы ответа клиенту HTTP прокси
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    ConnectionEstablished,
+    BadRequest,
+    Forbidden,
+    MethodNotAllowed,
+    BadGateway,
+    GatewayTimeout,
+}
+
+impl Status {
+    fn code(self) -> u16 {
+        match self {
+            Status::ConnectionEstablished => 200,
+            Status::BadRequest => 400,
+            Status::Forbidden => 403,
+            Status::MethodNotAllowed => 405,
+            Status::BadGateway => 502,
+            Status::GatewayTimeout => 504,
+        }
+    }
+
+    fn reason(self) -> &'static str {
+        match self {
+            Status::ConnectionEstablished => "Connection established",
+            Status::BadRequest => "Bad Request",
+            Status::Forbidden => "Forbidden",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::BadGateway => "Bad Gateway",
+            Status::GatewayTimeout => "Gateway Timeout",
+        }
+    }
+}
+
+
+/// Отправляет строку статуса; после ошибки соединение закрывается
+async fn respond(client_stream: &mut dyn ClientStream, status: Status) -> Result<()> {
+    let mut response = format!("HTTP/1.1 {} {}\r\n", status.code(), status.reason());
+    if status == Status::MethodNotAllowed {
+        response.push_str("Allow: CONNECT\r\n");
+    }
+    if status != Status::ConnectionEstablished {
+        response.push_str("Content-Length: 0\r\nConnection: close\r\n");
+    }
+    response.push_str("\r\n");
+    client_stream.write_all(response.as_bytes()).await?;
+    client_stream.flush().await?;
+    Ok(())
+}
+
+
+/// Читает строку запроса и заголовки до пустой строки. `None`, если клиент
+/// закрыл соединение раньше или прислал больше `MAX_HEAD_LEN` байт
+async fn read_head(client_stream: &mut BufReader<Box<dyn ClientStream>>) -> io::Result<Option<String>> {
+    let mut head = Vec::new();
+    loop {
+        let start = head.len();
+        let read = (&mut *client_stream).take((MAX_HEAD_LEN - start) as u64).read_until(b'\n', &mut head).await?;
+        if read == 0 || !head.ends_with(b"\n") {
+            return Ok(None);
+        }
+        if matches!(&head[start..], b"\r\n" | b"\n") {
+            return Ok(Some(String::from_utf8_lossy(&head).into_owned()));
+        }
+    }
+}
+
+
+/// Адрес назначения из запроса `CONNECT host:port HTTP/1.1`. Если в строке
+/// запроса нет порта, берётся заголовок Host
+fn parse_connect(head: &str) -> Result<(&str, u16), Status> {
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (request_line.next(), request_line.next(), request_line.next()) else {
+        return Err(Status::BadRequest);
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(Status::BadRequest);
+    }
+    if method != "CONNECT" {
+        return Err(Status::MethodNotAllowed);
+    }
+    if let Some(authority) = parse_authority(target) {
+        return Ok(authority);
+    }
+    lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .and_then(|(_, value)| parse_authority(value.trim()))
+        .ok_or(Status::BadRequest)
+}
+
+/// `host:port` или `[IPv6]:port`. Имя длиннее 255 байт не передать ни в
+/// SOCKS5 вышестоящему прокси, ни в DNS, поэтому такой запрос некорректен
+fn parse_authority(authority: &str) -> Option<(&str, u16)> {
+    let (host, port) = authority.rsplit_once(':')?;
+    let host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.strip_suffix(']')?,
+        None => host,
+    };
+    let port = port.parse().ok()?;
+    if !(1..=255).contains(&host.len()) || port == 0 {
+        return None;
+    }
+    Some((host, port))
+}
+
+
+/// Читает запрос CONNECT и резолвит его адрес. Ошибки уже залогированы;
+/// статус нужно отправить клиенту
+async fn read_request(client_stream: &mut BufReader<Box<dyn ClientStream>>, config: &Config) -> Result<Request, Status> {
+    let head = match read_head(client_stream).await {
+        Ok(Some(head)) => head,
+        Ok(None) => {
+            debug!("http request is incomplete or too long");
+            return Err(Status::BadRequest);
+        }
+        Err(e) => {
+            debug!("http request read failed: {}", e);
+            return Err(Status::BadRequest);
+        }
+    };
+    let (host, port) = parse_connect(&head).inspect_err(|status| {
+        warn!("http request rejected: {} {}", status.code(), status.reason());
+    })?;
+    let target = Address::Domain(host.as_bytes(), port);
+    if config.require_hostname && is_ip_literal(&target) {
+        config.metrics.ip_literal_rejected();
+        warn!("ip address request rejected (http request): {}", SocksError::IpLiteral);
+        return Err(Status::Forbidden);
+    }
+    if let Err(e) = check_blocked(&config.blocked_domains, &target) {
+        warn!("http request rejected: {}", e);
+        return Err(Status::Forbidden);
+    }
+    if config.upstream.is_some() && !is_ip_literal(&target) {
+        return Ok(Request::for_upstream(host.as_bytes(), port));
+    }
+    let addrs = dns::resolve_all(host.as_bytes(), port, config.dns.as_ref()).await.map_err(|e| {
+        warn!("http connect to {}:{} failed: {}", host, port, e);
+        Status::BadGateway
+    })?;
+    Ok(Request { command: Command::Connect, target_addr: addrs[0], fallback_addrs: addrs[1..].to_vec(), upstream_name: None })
+}
+
+
+/// Клиент HTTP прокси: CONNECT проходит тот же путь, что и CONNECT SOCKS5 -
+/// правила, резолвер, вышестоящий прокси, ограничения и журнал доступа,
+/// меняется только формат запроса и ответа. Аутентификации в HTTP нет,
+/// поэтому при настроенных пользователях клиенты получают отказ
+pub(crate) async fn handle_http_client(socket: TcpStream, connection: &ConnectionStats, config: &Config) {
+    let handshake_deadline = Instant::now() + config.handshake_timeout;
+    config.tune_socket(&socket);
+    let stream: Box<dyn ClientStream> = Box::new(socket);
+    let mut client_stream = BufReader::new(stream);
+    handle_http_request(&mut client_stream, connection, config, handshake_deadline).await;
+}
+
+/// Запрос HTTP прокси в уже принятом соединении: и на отдельном порту, и на
+/// порту SOCKS, где начало запроса прочитано в буфер при выборе протокола
+pub(crate) async fn handle_http_request(client_stream: &mut BufReader<Box<dyn ClientStream>>, connection: &ConnectionStats, config: &Config, handshake_deadline: Instant) {
+    let request = match timeout_at(handshake_deadline, read_request(client_stream, config)).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(status)) => {
+            config.metrics.connection_error();
+            let _ = respond(client_stream, status).await;
+            return;
+        }
+        Err(_) => {
+            config.metrics.connection_error();
+            info!("handshake timed out (http request)");
+            return;
+        }
+    };
+    let status = if !config.allows_anonymous() {
+        warn!("http proxy rejected: authentication is required");
+        Status::Forbidden
+    } else {
+        let allowed = config.allowed_addrs(&request);
+        if allowed.is_empty() {
+            warn!("connection to {} denied by rules", request.target_addr);
+            Status::Forbidden
+        } else {
+            match connect_any(&allowed, request.upstream_name.as_deref(), config).await {
+                Ok((mut target_stream, target_addr, connect_time)) => {
+                    info!("connected to {} over {} (http)", request.describe(target_addr), address_family(&request, &target_addr));
+                    if respond(client_stream, Status::ConnectionEstablished).await.is_ok() {
+                        let transferred = relay_target(&mut target_stream, client_stream, target_addr, connect_time, connection, config).await;
+                        info!("done to {}", request.describe(target_addr));
+                        config.log_access_as("HTTP", connection.peer, None, &request, Status::ConnectionEstablished.code(), transferred);
+                    }
+                    let _ = client_stream.shutdown().await;
+                    return;
+                }
+                Err(e) => {
+                    config.metrics.connection_error();
+                    warn!("connection to {} failed: {}", request.describe(request.target_addr), e);
+                    match error_reply(&e) {
+                        SOCKSReply::TTLExpired => Status::GatewayTimeout,
+                        _ => Status::BadGateway,
+                    }
+                }
+            }
+        }
+    };
+    let _ = respond(client_stream, status).await;
+    config.log_access_as("HTTP", connection.peer, None, &request, status.code(), None);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_length() {
+        let host = "a".repeat(255);
+        let head = format!("CONNECT {}:443 HTTP/1.1\r\n\r\n", host);
+        assert_eq!(parse_connect(&head), Ok((host.as_str(), 443)));
+
+        let host = "a".repeat(256);
+        let head = format!("CONNECT {}:443 HTTP/1.1\r\n\r\n", host);
+        assert_eq!(parse_connect(&head), Err(Status::BadRequest));
+        let head = format!("CONNECT / HTTP/1.1\r\nHost: {}:443\r\n\r\n", host);
+        assert_eq!(parse_connect(&head), Err(Status::BadRequest));
+        assert_eq!(parse_connect("CONNECT :443 HTTP/1.1\r\n\r\n"), Err(Status::BadRequest));
+    }
+}
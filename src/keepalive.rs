@@ -0,0 +1,82 @@
+use std::io;
+use std::time::Duration;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+use tracing::warn;
+
+
+/// Параметры TCP keepalive для соединений с клиентами и целями. Без них
+/// простаивающие туннели (например, SSH) молча разрывают NAT и файрволы
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    /// Сколько соединение простаивает до первой пробы
+    pub time: Duration,
+    /// Интервал между пробами; без него - системный
+    pub interval: Option<Duration>,
+    /// Сколько проб без ответа до разрыва; без него - системное
+    pub retries: Option<u32>,
+}
+
+impl Keepalive {
+    pub fn new(time: Duration) -> Keepalive {
+        Keepalive { time, interval: None, retries: None }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Keepalive {
+        self.interval = Some(interval);
+        self
+    }
+
+    pub fn with_retries(mut self, retries: u32) -> Keepalive {
+        self.retries = Some(retries);
+        self
+    }
+
+    /// Предупреждает, если интервал и число проб на этой платформе не задаются
+    pub(crate) fn warn_unsupported(&self) {
+        if !INTERVAL_SUPPORTED && (self.interval.is_some() || self.retries.is_some()) {
+            warn!("TCP keepalive interval and probe count are not supported on this platform, using system defaults");
+        }
+    }
+
+    /// Включает SO_KEEPALIVE с этими параметрами на сокете
+    pub(crate) fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        #[allow(unused_mut)]
+        let mut keepalive = TcpKeepalive::new().with_time(self.time);
+        #[cfg(any(
+            target_os = "android",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "fuchsia",
+            target_os = "illumos",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            target_os = "windows",
+        ))]
+        {
+            if let Some(interval) = self.interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            if let Some(retries) = self.retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+        }
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)
+    }
+}
+
+
+const INTERVAL_SUPPORTED: bool = cfg!(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "illumos",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "windows",
+));
@@ -0,0 +1,2044 @@
+use std::net::{SocketAddr, IpAddr, Ipv6Addr};
+use std::path::PathBuf;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use anyhow::{Context as _, Result};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::{timeout, timeout_at};
+use tokio_rustls::TlsAcceptor;
+#[cfg(unix)]
+use socket2::{Domain, Protocol, Socket, Type};
+use tracing::{debug, info, info_span, instrument, warn, Instrument};
+use socks4::Socks4Reply;
+use acl::{AccessControl, DomainMatcher, TargetList};
+use port_range::PortRange;
+use dns::{AddressPreference, DnsResolver};
+use shutdown::Shutdown;
+use metrics::Metrics;
+use stats::{ConnectionStats, StatsHandle};
+use conn_limit::ConnectionLimit;
+use log::{AccessLogger, LogEntry};
+use rate_limit::Throttle;
+use keepalive::Keepalive;
+use stream::ClientStream;
+use unix_socket::{UnixSocketListener, UNIX_CLIENT_ADDR};
+use parse::{Address, ParseError};
+use auth::{AuthMethod, AuthOutcome, ClientInfo, NoAuth, UsernamePassword};
+
+pub mod acl;
+pub mod auth;
+mod bind;
+pub use bind::DEFAULT_ACCEPT_TIMEOUT;
+mod http_connect;
+pub mod obfs;
+pub mod keepalive;
+pub mod log;
+pub mod metrics;
+pub mod rate_limit;
+pub mod client;
+pub mod conn_limit;
+pub mod dns;
+pub mod shutdown;
+pub mod stats;
+pub mod stream;
+pub mod systemd;
+pub mod tls;
+pub mod parse;
+pub mod port_range;
+mod proxy_protocol;
+mod socks4;
+pub mod udp;
+pub mod unix_socket;
+
+
+#[repr(u8)]
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum SOCKSReply {
+    Succeeded = 0x00,
+    GeneralSOCKSServerFailture = 0x01,
+    ConnectionNotAllowedByRuleset = 0x02,
+    NetworkUnreachable = 0x03,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    TTLExpired = 0x06,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+impl SOCKSReply {
+    /// Код ответа для ошибки ввода-вывода
+    pub fn from_io_error(e: &io::Error) -> SOCKSReply {
+        SOCKSReply::from_io(e.kind(), e.raw_os_error())
+    }
+
+    /// Код ответа по виду ошибки и, для видов, которые std не различает,
+    /// по коду ошибки ОС
+    pub fn from_io(kind: io::ErrorKind, raw_os: Option<i32>) -> SOCKSReply {
+        match kind {
+            io::ErrorKind::ConnectionRefused => SOCKSReply::ConnectionRefused,
+            io::ErrorKind::HostUnreachable | io::ErrorKind::AddrNotAvailable => SOCKSReply::HostUnreachable,
+            io::ErrorKind::NetworkUnreachable | io::ErrorKind::NetworkDown => SOCKSReply::NetworkUnreachable,
+            io::ErrorKind::TimedOut => SOCKSReply::TTLExpired,
+            // Так ядро отвечает на соединения, запрещённые файрволом
+            io::ErrorKind::PermissionDenied => SOCKSReply::ConnectionNotAllowedByRuleset,
+            _ if raw_os == Some(os_error::EHOSTDOWN) => SOCKSReply::HostUnreachable,
+            _ => SOCKSReply::GeneralSOCKSServerFailture,
+        }
+    }
+
+    /// Код ответа по байту REP; неизвестные значения считаются общей ошибкой
+    pub fn from_code(code: u8) -> SOCKSReply {
+        match code {
+            0x00 => SOCKSReply::Succeeded,
+            0x02 => SOCKSReply::ConnectionNotAllowedByRuleset,
+            0x03 => SOCKSReply::NetworkUnreachable,
+            0x04 => SOCKSReply::HostUnreachable,
+            0x05 => SOCKSReply::ConnectionRefused,
+            0x06 => SOCKSReply::TTLExpired,
+            0x07 => SOCKSReply::CommandNotSupported,
+            0x08 => SOCKSReply::AddressTypeNotSupported,
+            _ => SOCKSReply::GeneralSOCKSServerFailture,
+        }
+    }
+}
+
+
+/// Коды ошибок ОС без своего `io::ErrorKind`
+mod os_error {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub const EHOSTDOWN: i32 = 112;
+    #[cfg(windows)]
+    pub const EHOSTDOWN: i32 = 10064;
+    #[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+    pub const EHOSTDOWN: i32 = 64;
+}
+
+
+/// Ошибки обработки запроса. Каждый вариант знает, какой код ответа
+/// получит клиент, поэтому обработчикам не нужно угадывать его по тексту
+#[derive(Debug)]
+pub enum SocksError {
+    UnsupportedVersion(u8),
+    /// Нарушение формата запроса (ненулевой RSV, пустое доменное имя):
+    /// обычно признак рассинхронизации или вовсе не SOCKS клиента
+    MalformedRequest,
+    /// Сообщение рукопожатия длиннее, чем допускает протокол
+    HandshakeTooLong(usize),
+    FieldTooLong,
+    UnsupportedCommand,
+    UnsupportedAddressType,
+    /// Резолвер не ответил или ответил ошибкой
+    ResolutionFailed,
+    /// Резолвер ответил, но адресов у имени нет
+    NoAddresses,
+    /// Порт назначения 0: подключение к нему зависит от ОС
+    ZeroPort,
+    /// Адрес запрещён правилами
+    PolicyDenied,
+    /// Цель задана IP адресом, а сервер требует доменное имя
+    IpLiteral,
+    /// Имя цели (или IP в текстовом виде) совпало с `--block-domain`
+    DomainBlocked(String),
+    ConnectFailed(io::Error),
+    /// Истекло ожидание; внутри - чего именно ждали
+    Timeout(&'static str),
+    /// Вышестоящий прокси отказал в соединении с кодом REP
+    UpstreamFailure(u8),
+    /// Вышестоящий прокси ответил не по протоколу; клиент не виноват, и
+    /// коды про его запрос (например, 0x08) ему не отправляются
+    MalformedUpstreamReply,
+    MalformedDatagram,
+    FragmentedDatagram,
+}
+
+impl SocksError {
+    pub fn reply_code(&self) -> SOCKSReply {
+        match self {
+            SocksError::UnsupportedCommand => SOCKSReply::CommandNotSupported,
+            SocksError::UnsupportedAddressType => SOCKSReply::AddressTypeNotSupported,
+            SocksError::ResolutionFailed => SOCKSReply::HostUnreachable,
+            SocksError::NoAddresses => SOCKSReply::NetworkUnreachable,
+            SocksError::ZeroPort
+            | SocksError::PolicyDenied
+            | SocksError::IpLiteral
+            | SocksError::DomainBlocked(_) => SOCKSReply::ConnectionNotAllowedByRuleset,
+            SocksError::ConnectFailed(e) => SOCKSReply::from_io_error(e),
+            SocksError::Timeout(_) => SOCKSReply::TTLExpired,
+            // Ответ вышестоящего прокси передаётся клиенту как есть
+            SocksError::UpstreamFailure(code) => SOCKSReply::from_code(*code),
+            SocksError::UnsupportedVersion(_)
+            | SocksError::MalformedRequest
+            | SocksError::HandshakeTooLong(_)
+            | SocksError::FieldTooLong
+            | SocksError::MalformedUpstreamReply
+            | SocksError::MalformedDatagram
+            | SocksError::FragmentedDatagram => SOCKSReply::GeneralSOCKSServerFailture,
+        }
+    }
+}
+
+impl std::fmt::Display for SocksError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SocksError::UnsupportedVersion(version) => write!(f, "Unsupported protocol version {:#04x}", version),
+            SocksError::MalformedRequest => write!(f, "Request header is malformed"),
+            SocksError::HandshakeTooLong(max) => write!(f, "Handshake message exceeds {} bytes", max),
+            SocksError::FieldTooLong => write!(f, "Request field is too long"),
+            SocksError::UnsupportedCommand => write!(f, "Method is not allowed"),
+            SocksError::UnsupportedAddressType => write!(f, "Address type is not allowed"),
+            SocksError::ResolutionFailed => write!(f, "Domain name could not be resolved"),
+            SocksError::NoAddresses => write!(f, "Domain name has no addresses"),
+            SocksError::ZeroPort => write!(f, "Destination port 0 is not allowed"),
+            SocksError::PolicyDenied => write!(f, "Destination is denied by rules"),
+            SocksError::IpLiteral => write!(f, "Destination must be a host name, not an IP address"),
+            SocksError::DomainBlocked(name) => write!(f, "Destination {} is blocked", name),
+            SocksError::ConnectFailed(e) => write!(f, "{}", e),
+            SocksError::Timeout(what) => write!(f, "Timed out {}", what),
+            SocksError::UpstreamFailure(code) => write!(f, "Proxy replied with error {:#04x}", code),
+            SocksError::MalformedUpstreamReply => write!(f, "Proxy sent a malformed reply"),
+            SocksError::MalformedDatagram => write!(f, "UDP datagram header is malformed"),
+            SocksError::FragmentedDatagram => write!(f, "UDP datagram fragmentation is not supported"),
+        }
+    }
+}
+
+impl std::error::Error for SocksError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SocksError::ConnectFailed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Код ответа для ошибки обработчика: по варианту `SocksError`, для
+/// ошибок ввода-вывода - по их виду, для остального - общая ошибка
+fn error_reply(e: &anyhow::Error) -> SOCKSReply {
+    if let Some(e) = e.downcast_ref::<SocksError>() {
+        e.reply_code()
+    } else if let Some(e) = e.downcast_ref::<io::Error>() {
+        SOCKSReply::from_io_error(e)
+    } else {
+        SOCKSReply::GeneralSOCKSServerFailture
+    }
+}
+
+
+/// Дописывает ATYP, адрес и порт в формате SOCKS5
+pub(crate) fn push_socket_addr(buf: &mut Vec<u8>, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(v4) => {
+            buf.push(AddressType::V4 as u8);
+            buf.extend_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            buf.push(AddressType::V6 as u8);
+            buf.extend_from_slice(&v6.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Ответ на запрос SOCKS5; VER в нём всегда 0x05. Ответы SOCKS4 - `socks4::reply`
+pub async fn reply(client_stream: &mut dyn ClientStream, reply: SOCKSReply, target_addr: &SocketAddr) -> Result<()> {
+    let mut reply = vec![SOCKS5_VERSION, reply as u8, 0x00];
+    // ATYP и BND.ADDR обязательны, иначе клиент не сможет разобрать ответ
+    push_socket_addr(&mut reply, target_addr);
+    client_stream.write_all(&reply).await?;
+    client_stream.flush().await?;
+    Ok(())
+}
+
+pub const SOCKS4_VERSION: u8 = 0x04;
+pub const SOCKS5_VERSION: u8 = 0x05;
+
+pub const METHOD_NO_AUTH: u8 = 0x00;
+pub const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+
+/// Дочитывает в `buf` сообщение, пока `parse` сообщает о нехватке байт.
+/// Читается ровно недостающее, чтобы не захватить данные клиента, идущие следом
+pub(crate) async fn read_message<T>(client_stream: &mut dyn ClientStream, buf: &mut Vec<u8>, parse: impl Fn(&[u8]) -> Result<T, ParseError>) -> Result<()> {
+    loop {
+        let more = match parse(buf) {
+            Ok(_) => return Ok(()),
+            Err(ParseError::NeedMore(more)) => more,
+            Err(ParseError::Malformed(e)) => return Err(e),
+        };
+        let start = buf.len();
+        buf.resize(start + more, 0);
+        client_stream.read_exact(&mut buf[start..]).await?;
+    }
+}
+
+#[instrument(level = "debug", skip_all)]
+async fn process_method<'a>(client_stream: &mut dyn ClientStream, methods: &'a [Box<dyn AuthMethod>]) -> Result<Option<&'a dyn AuthMethod>> /* None if no method fits */ {
+    let mut buf = Vec::new();
+    read_message(client_stream, &mut buf, |buf| parse::parse_greeting(buf).map(drop)).await?;
+    // Разбор дешёвый, поэтому готовое сообщение просто разбирается ещё раз
+    let greeting = parse::parse_greeting(&buf).map_err(ParseError::into_error)?;
+
+    // Приветствие без методов некорректно (RFC 1928): отказываем сразу и
+    // больше ничего не читаем от такого клиента
+    if greeting.methods.is_empty() {
+        warn!("greeting offers no auth methods");
+        client_stream.write_all(&[SOCKS5_VERSION, METHOD_NO_ACCEPTABLE]).await?;
+        client_stream.flush().await?;
+        return Ok(None);
+    }
+
+    // Пересекаем предложенные клиентом методы с включёнными на сервере;
+    // из подходящих выбирается первый в порядке приоритета сервера, и в
+    // ответе всегда один метод. По умолчанию (см. `Config::finish_auth`)
+    // с пользователями включён только 0x02, без них - только 0x00, так что
+    // клиент, предложивший оба, получает 0x02 или 0x00 соответственно
+    let chosen_method = methods
+        .iter()
+        .find(|method| greeting.methods.contains(&method.id()));
+
+    match chosen_method {
+        Some(method) => {
+            client_stream.write_all(&[SOCKS5_VERSION, method.id()]).await?;
+            client_stream.flush().await?;
+            Ok(Some(method.as_ref()))
+        }
+        None => {
+            client_stream.write_all(&[SOCKS5_VERSION, METHOD_NO_ACCEPTABLE]).await?;
+            client_stream.flush().await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Команда запроса SOCKS5 (CMD)
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Connect = 0x01,
+    Bind = 0x02,
+    UdpAssociate = 0x03,
+}
+
+impl Command {
+    /// Название для журнала доступа
+    pub fn name(self) -> &'static str {
+        match self {
+            Command::Connect => "CONNECT",
+            Command::Bind => "BIND",
+            Command::UdpAssociate => "UDP_ASSOCIATE",
+        }
+    }
+}
+
+impl TryFrom<u8> for Command {
+    type Error = SocksError;
+
+    fn try_from(value: u8) -> Result<Command, SocksError> {
+        match value {
+            0x01 => Ok(Command::Connect),
+            0x02 => Ok(Command::Bind),
+            0x03 => Ok(Command::UdpAssociate),
+            _ => Err(SocksError::UnsupportedCommand),
+        }
+    }
+}
+
+/// Тип адреса SOCKS5 (ATYP)
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressType {
+    V4 = 0x01,
+    Domain = 0x03,
+    V6 = 0x04,
+}
+
+impl AddressType {
+    /// Длина адреса этого типа; у домена она записана в первом байте адреса
+    pub fn addr_len(self) -> Option<usize> {
+        match self {
+            AddressType::V4 => Some(4),
+            AddressType::Domain => None,
+            AddressType::V6 => Some(16),
+        }
+    }
+}
+
+impl TryFrom<u8> for AddressType {
+    type Error = SocksError;
+
+    fn try_from(value: u8) -> Result<AddressType, SocksError> {
+        match value {
+            0x01 => Ok(AddressType::V4),
+            0x03 => Ok(AddressType::Domain),
+            0x04 => Ok(AddressType::V6),
+            _ => Err(SocksError::UnsupportedAddressType),
+        }
+    }
+}
+
+/// Разобранный запрос SOCKS5
+pub struct Request {
+    pub command: Command,
+    pub target_addr: SocketAddr,
+    /// Остальные адреса домена: к ним пробуем подключиться по порядку,
+    /// если к `target_addr` не удалось. Для IP адресов пуст
+    pub fallback_addrs: Vec<SocketAddr>,
+    /// Имя цели CONNECT, которое резолвит вышестоящий прокси, а не сервер:
+    /// тогда `target_addr` - нулевой IP с портом цели, а других адресов нет
+    pub upstream_name: Option<Vec<u8>>,
+}
+
+impl Request {
+    /// Все адреса цели в порядке попыток подключения
+    pub fn addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        std::iter::once(self.target_addr).chain(self.fallback_addrs.iter().copied())
+    }
+
+    /// Запрос к имени без резолвинга, для вышестоящего прокси
+    pub(crate) fn for_upstream(name: &[u8], port: u16) -> Request {
+        Request {
+            command: Command::Connect,
+            target_addr: SocketAddr::from(([0, 0, 0, 0], port)),
+            fallback_addrs: Vec::new(),
+            upstream_name: Some(name.to_vec()),
+        }
+    }
+
+    /// Цель для логов: имя для вышестоящего прокси или адрес `addr`
+    pub fn describe(&self, addr: SocketAddr) -> String {
+        match &self.upstream_name {
+            Some(name) => format!("{}:{}", String::from_utf8_lossy(name), addr.port()),
+            None => addr.to_string(),
+        }
+    }
+}
+
+/// IPv4-mapped адрес (`::ffff:a.b.c.d`) превращается в обычный IPv4: подключаться
+/// к нему через IPv6 сокет ненадёжно, а правила для IPv4 иначе бы его пропустили
+pub(crate) fn unmap_ipv6(ip: Ipv6Addr) -> IpAddr {
+    match ip.to_ipv4_mapped() {
+        Some(ipv4) => IpAddr::V4(ipv4),
+        None => IpAddr::V6(ip),
+    }
+}
+
+/// IPv4-mapped адрес сравнивается как IPv4: на IPv6 сокете клиент с IPv4
+/// виден именно так, а в запросе мог указать обычный IPv4
+pub(crate) fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => unmap_ipv6(v6),
+        v4 => v4,
+    }
+}
+
+/// Цель - IP адрес, в том числе текстом в поле имени: такое имя резолвится
+/// без DNS, и для `require_hostname` ничем не отличается от IP
+pub(crate) fn is_ip_literal(target: &Address) -> bool {
+    match target {
+        Address::Ip(_) => true,
+        Address::Domain(domain, _) => std::str::from_utf8(domain).is_ok_and(|text| text.parse::<IpAddr>().is_ok()),
+    }
+}
+
+/// Ошибка, если имя цели CONNECT запрещено `blocked`. IP адрес проверяется
+/// в текстовом виде
+pub(crate) fn check_blocked(blocked: &DomainMatcher, target: &Address) -> Result<(), SocksError> {
+    if blocked.is_empty() {
+        return Ok(());
+    }
+    let name = match target {
+        Address::Ip(addr) => addr.ip().to_string(),
+        Address::Domain(domain, _) => String::from_utf8_lossy(domain).into_owned(),
+    };
+    if blocked.matches(name.as_bytes()) {
+        return Err(SocksError::DomainBlocked(name));
+    }
+    Ok(())
+}
+
+/// Доменные имена резолвятся через `dns`, если он задан, иначе системным
+/// резолвером; с `upstream_resolves` имя цели CONNECT не резолвится, его
+/// получает вышестоящий прокси.
+/// С `require_hostname` CONNECT к IP адресу (и к IP текстом в поле имени)
+/// отклоняется с `SocksError::IpLiteral`,
+/// CONNECT к имени из `blocked` - с `SocksError::DomainBlocked`, до резолвинга
+#[instrument(level = "debug", skip_all)]
+pub async fn process_request(client_stream: &mut dyn ClientStream, dns: Option<&DnsResolver>, require_hostname: bool, blocked: &DomainMatcher, upstream_resolves: bool) -> Result<Request> {
+    let mut buf = Vec::new();
+    read_message(client_stream, &mut buf, |buf| parse::parse_request(buf).map(drop)).await?;
+    let header = parse::parse_request(&buf).map_err(ParseError::into_error)?;
+    if header.command == Command::Connect {
+        if require_hostname && is_ip_literal(&header.target) {
+            return Err(SocksError::IpLiteral.into());
+        }
+        check_blocked(blocked, &header.target)?;
+        // IP, записанный в поле имени, проверяется правилами как обычный адрес
+        if let (true, false, Address::Domain(domain, port)) = (upstream_resolves, is_ip_literal(&header.target), &header.target) {
+            return Ok(Request::for_upstream(domain, *port));
+        }
+    }
+
+    let (target_addr, fallback_addrs) = match header.target {
+        Address::Ip(addr) => (addr, Vec::new()),
+        Address::Domain(domain, port) => {
+            // Имя резолвится как есть, даже если это не UTF-8. Ошибка или пустой
+            // результат резолвинга отвечается HostUnreachable
+            let mut addrs = dns::resolve_all(domain, port, dns).await?;
+            (addrs.remove(0), addrs)
+        }
+    };
+    Ok(Request { command: header.command, target_addr, fallback_addrs, upstream_name: None })
+}
+
+
+/// Время последней передачи данных, общее для ретрансляции и сторожа простоя
+struct Activity {
+    start: Instant,
+    /// Миллисекунды от `start`
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity { start: Instant::now(), last: AtomicU64::new(0) }
+    }
+
+    fn touch(&self) {
+        self.last.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
+
+
+/// Поток клиента, учитывающий переданные байты в `metrics` и отмечающий активность.
+/// Всё, что идёт к цели, читается из клиента, а всё от цели пишется клиенту,
+/// поэтому учёта и ограничения скорости на стороне клиента достаточно для обоих направлений
+struct Tracked<'a> {
+    inner: &'a mut dyn ClientStream,
+    metrics: &'a Metrics,
+    activity: &'a Activity,
+    /// Ограничения скорости от клиента к цели и от цели к клиенту, независимые
+    upload: Option<Throttle>,
+    download: Option<Throttle>,
+    /// Байт передано в обе стороны; счётчик виден и статистике соединения
+    transferred: &'a AtomicU64,
+}
+
+impl AsyncRead for Tracked<'_> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if let Some(upload) = &mut self.upload {
+            ready!(upload.poll_ready(cx));
+        }
+        let before = buf.filled().len();
+        let result = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        if n > 0 {
+            self.metrics.add_received(n);
+            self.activity.touch();
+            self.transferred.fetch_add(n as u64, Ordering::Relaxed);
+            if let Some(upload) = &mut self.upload {
+                upload.record(n);
+            }
+        }
+        result
+    }
+}
+
+impl AsyncWrite for Tracked<'_> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if let Some(download) = &mut self.download {
+            ready!(download.poll_ready(cx));
+        }
+        let result = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.metrics.add_sent(n);
+            self.activity.touch();
+            self.transferred.fetch_add(n as u64, Ordering::Relaxed);
+            if let Some(download) = &mut self.download {
+                download.record(n);
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+
+/// Завершается, когда с последней передачи данных прошло `timeout`
+async fn idle_expired(activity: &Activity, timeout: Duration) {
+    loop {
+        let deadline = activity.last() + timeout;
+        if Instant::now() >= deadline {
+            return;
+        }
+        tokio::time::sleep_until(deadline.into()).await;
+    }
+}
+
+
+/// Ретранслирует данные в обе стороны, пока обе стороны не закроются
+/// или соединение не простоит дольше `idle_timeout`. Переданные байты учитываются в `metrics`.
+/// `rate_limit` ограничивает скорость (байт в секунду) каждого направления отдельно,
+/// `buffer_size` - размер буфера каждого направления. Переданные в обе стороны
+/// байты по ходу прибавляются к `transferred`; возвращается их итог
+#[instrument(name = "relay", level = "debug", skip_all)]
+pub async fn relay(target_stream: &mut TcpStream, client_stream: &mut dyn ClientStream, idle_timeout: Option<Duration>, rate_limit: Option<u64>, buffer_size: usize, metrics: &Metrics, transferred: &AtomicU64) -> Result<u64> {
+    let activity = Activity::new();
+    let before = transferred.load(Ordering::Relaxed);
+    let mut client = Tracked {
+        inner: client_stream,
+        metrics,
+        activity: &activity,
+        upload: rate_limit.map(Throttle::new),
+        download: rate_limit.map(Throttle::new),
+        transferred,
+    };
+
+    let copied = {
+        let copy = tokio::io::copy_bidirectional_with_sizes(&mut client, target_stream, buffer_size, buffer_size);
+        match idle_timeout {
+            Some(timeout) => tokio::select! {
+                copied = copy => Some(copied),
+                // Ответ SOCKS уже отправлен, поэтому по таймауту простоя просто закрываемся
+                _ = idle_expired(&activity, timeout) => {
+                    info!("relay idle timeout");
+                    None
+                }
+            },
+            None => Some(copy.await),
+        }
+    };
+    let relayed = transferred.load(Ordering::Relaxed) - before;
+    match copied {
+        None | Some(Ok(_)) => Ok(relayed),
+        Some(Err(e)) if is_disconnect(&e) => {
+            debug!("relay closed: {}", e);
+            Ok(relayed)
+        }
+        Some(Err(e)) => Err(e.into()),
+    }
+}
+
+
+/// Обрыв соединения одной из сторон (в том числе TLS без close_notify) -
+/// обычное завершение ретрансляции, а не ошибка
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe
+    )
+}
+
+
+/// Размер буфера ретрансляции по умолчанию
+pub const DEFAULT_BUFFER_SIZE: usize = 8192;
+/// Допустимые размеры буфера ретрансляции (степени двойки)
+pub const BUFFER_SIZE_RANGE: std::ops::RangeInclusive<usize> = 512..=65536;
+
+/// Сколько по умолчанию ждать приветствия, аутентификации и запроса вместе
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Логирует и учитывает ошибку рукопожатия
+fn log_handshake_error(stage: &str, peer: SocketAddr, e: &anyhow::Error, metrics: &Metrics) {
+    match e.downcast_ref() {
+        // Отказ по настройкам, а не ошибка; адрес нужен, чтобы найти
+        // приложение, которое резолвит имена само
+        Some(SocksError::IpLiteral) => {
+            metrics.ip_literal_rejected();
+            warn!("ip address request from {} rejected ({}): {}", peer, stage, e);
+        }
+        // Мусор вместо рукопожатия - повод присмотреться к клиенту, поэтому с адресом
+        Some(SocksError::HandshakeTooLong(_) | SocksError::MalformedRequest) => {
+            metrics.connection_error();
+            warn!("malformed handshake from {} ({}): {}", peer, stage, e);
+        }
+        _ => {
+            metrics.connection_error();
+            warn!("{} error: {}", stage, e);
+        }
+    }
+}
+
+
+/// Обслуживает соединение со слушателя SOCKS: адрес клиента, лимит
+/// соединений, учёт в статистике и обработчик
+async fn serve_socks(mut client_stream: TcpStream, peer: SocketAddr, local: SocketAddr, config: Arc<Config>) {
+    let Some((peer, local)) = config.client_addrs(&mut client_stream, peer, local).await else {
+        return;
+    };
+    // Все сообщения обработчика помечаются адресом клиента
+    async {
+        debug!("new connection");
+        let Some(_slot) = config.conn_limit.acquire(peer.ip()) else {
+            // Открытый ответ при обфускации выдал бы протокол и сбил клиенту ключ
+            let mut client_stream: Box<dyn ClientStream> = match &config.obfs {
+                Some(key) => Box::new(obfs::XorStream::new(client_stream, key.clone())),
+                None => Box::new(client_stream),
+            };
+            // Ответ без TLS клиент TLS всё равно не прочитает
+            if config.tls.is_none() {
+                let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+                let _ = reply(&mut client_stream, SocksError::PolicyDenied.reply_code(), &unspecified).await;
+            }
+            config.reset_on_close(&client_stream);
+            return;
+        };
+        let _active = config.metrics.track_connection();
+        let connection = config.stats.register(peer, local);
+        handle_client(client_stream, &connection, &config).await;
+    }
+        .instrument(info_span!("conn", peer = %peer))
+        .await
+}
+
+/// То же для слушателя HTTP CONNECT
+async fn serve_http(mut client_stream: TcpStream, peer: SocketAddr, local: SocketAddr, config: Arc<Config>) {
+    let Some((peer, local)) = config.client_addrs(&mut client_stream, peer, local).await else {
+        return;
+    };
+    async {
+        debug!("new connection");
+        let Some(_slot) = config.conn_limit.acquire(peer.ip()) else {
+            config.reset_on_close(&client_stream);
+            return;
+        };
+        let _active = config.metrics.track_connection();
+        let connection = config.stats.register(peer, local);
+        http_connect::handle_http_client(client_stream, &connection, &config).await;
+    }
+        .instrument(info_span!("http", peer = %peer))
+        .await
+}
+
+/// То же для Unix сокета: адресов нет, PROXY protocol не читается, все
+/// клиенты делят лимит соединений `UNIX_CLIENT_ADDR`
+async fn serve_unix(mut client_stream: Box<dyn ClientStream>, config: Arc<Config>) {
+    async {
+        debug!("new connection");
+        let Some(_slot) = config.conn_limit.acquire(UNIX_CLIENT_ADDR.ip()) else {
+            let _ = reply(&mut client_stream, SocksError::PolicyDenied.reply_code(), &UNIX_CLIENT_ADDR).await;
+            return;
+        };
+        let _active = config.metrics.track_connection();
+        let connection = config.stats.register(UNIX_CLIENT_ADDR, UNIX_CLIENT_ADDR);
+        let handshake_deadline = tokio::time::Instant::now() + config.handshake_timeout;
+        handle_stream(client_stream, &connection, &config, handshake_deadline).await;
+    }
+        .instrument(info_span!("unix"))
+        .await
+}
+
+
+async fn handle_client(socket: TcpStream, connection: &ConnectionStats, config: &Config) {
+    // Клиент, который подключился и молчит, не должен держать соединение вечно:
+    // TLS, приветствие, аутентификация и запрос должны уложиться в общий срок
+    let handshake_deadline = tokio::time::Instant::now() + config.handshake_timeout;
+    config.tune_socket(&socket);
+
+    let stream: Box<dyn ClientStream> = match &config.tls {
+        Some(tls) => match timeout_at(handshake_deadline, tls.accept(socket)).await {
+            Ok(Ok(stream)) => Box::new(stream),
+            Ok(Err(e)) => {
+                debug!("tls handshake failed: {}", e);
+                return;
+            }
+            Err(_) => {
+                info!("handshake timed out (tls)");
+                return;
+            }
+        },
+        None => match &config.obfs {
+            Some(key) => Box::new(obfs::XorStream::new(socket, key.clone())),
+            None => Box::new(socket),
+        },
+    };
+    handle_stream(stream, connection, config, handshake_deadline).await;
+}
+
+/// Выбирает протокол по первому байту и обслуживает клиента; TLS, если он
+/// есть, уже снят
+async fn handle_stream(stream: Box<dyn ClientStream>, connection: &ConnectionStats, config: &Config, handshake_deadline: tokio::time::Instant) {
+    // Рукопожатие и ретрансляция идут через один буфер: данные, которые клиент
+    // прислал сразу за запросом (например, TLS ClientHello), уходят цели первыми
+    let mut client_stream = BufReader::new(stream);
+
+    // Версию протокола смотрим без извлечения: обработчики читают запрос целиком
+    let version = match timeout_at(handshake_deadline, client_stream.fill_buf()).await {
+        Ok(Ok(buf)) => buf.first().copied(),
+        Ok(Err(_)) => None,
+        Err(_) => {
+            info!("handshake timed out (greeting)");
+            return;
+        }
+    };
+    match version {
+        Some(SOCKS4_VERSION) => handle_socks4_client(&mut client_stream, connection, config, handshake_deadline).await,
+        Some(SOCKS5_VERSION) => handle_socks5_client(&mut client_stream, connection, config, handshake_deadline).await,
+        // Метод HTTP начинается с заглавной буквы; такого байта версии в SOCKS нет
+        Some(b'A'..=b'Z') if config.sniff_http => {
+            http_connect::handle_http_request(&mut client_stream, connection, config, handshake_deadline).await
+        }
+        Some(version) => {
+            // HTTP-клиенты и сканеры портов не получают даже частичного ответа
+            warn!("unsupported protocol version {:#04x}", version);
+        }
+        None => {
+            debug!("connection closed before greeting");
+        }
+    }
+    if !connection.succeeded() {
+        config.reset_on_close(&client_stream);
+    }
+    // Для TLS отправляет close_notify, чтобы клиент знал, что данные не обрезаны
+    let _ = client_stream.shutdown().await;
+    if let Some(linger) = config.linger.filter(|_| connection.succeeded()) {
+        // Сокет не закрывается, пока клиент не дочитал данные и не закрыл своё направление
+        let _ = timeout(linger, tokio::io::copy(&mut client_stream, &mut tokio::io::sink())).await;
+    }
+}
+
+
+async fn handle_socks4_client(client_stream: &mut dyn ClientStream, connection: &ConnectionStats, config: &Config, handshake_deadline: tokio::time::Instant) {
+    let request = match timeout_at(handshake_deadline, socks4::process_socks4_request(client_stream, config.dns.as_ref(), config.require_hostname, &config.blocked_domains, config.upstream.is_some())).await {
+        Ok(request) => request,
+        Err(_) => {
+            config.metrics.connection_error();
+            info!("handshake timed out (socks4 request)");
+            return;
+        }
+    };
+    match request {
+        Ok(request) if config.unexpected_data(client_stream) => {
+            let _ = socks4::reply(client_stream, Socks4Reply::Rejected, &request.target_addr).await;
+            config.log_access(connection.peer, SOCKS4_VERSION, None, &request, Socks4Reply::Rejected as u8, None);
+        }
+        // В SOCKS4 нет проверки пароля, поэтому при настроенной аутентификации он запрещён
+        Ok(request) if !config.allows_anonymous() => {
+            warn!("socks4 rejected: authentication is required");
+            let _ = socks4::reply(client_stream, Socks4Reply::Rejected, &request.target_addr).await;
+            config.log_access(connection.peer, SOCKS4_VERSION, None, &request, Socks4Reply::Rejected as u8, None);
+        }
+        Ok(request) => {
+            let target_addr = request.target_addr;
+            let allowed = config.allowed_addrs(&request);
+            if allowed.is_empty() {
+                warn!("socks4 connection to {} denied by rules", target_addr);
+                let _ = socks4::reply(client_stream, Socks4Reply::Rejected, &target_addr).await;
+                config.log_access(connection.peer, SOCKS4_VERSION, None, &request, Socks4Reply::Rejected as u8, None);
+            } else if let Ok((mut target_stream, target_addr, connect_time)) = connect_any(&allowed, request.upstream_name.as_deref(), config).await {
+                info!("connected to {} over {}", request.describe(target_addr), address_family(&request, &target_addr));
+                if socks4::reply(client_stream, Socks4Reply::Granted, &target_addr).await.is_ok() {
+                    let transferred = relay_target(&mut target_stream, client_stream, target_addr, connect_time, connection, config).await;
+                    info!("done to {}", request.describe(target_addr));
+                    config.log_access(connection.peer, SOCKS4_VERSION, None, &request, Socks4Reply::Granted as u8, transferred);
+                }
+            } else {
+                config.metrics.connection_error();
+                warn!("connection to {} failed", request.describe(target_addr));
+                let _ = socks4::reply(client_stream, Socks4Reply::Rejected, &target_addr).await;
+                config.log_access(connection.peer, SOCKS4_VERSION, None, &request, Socks4Reply::Rejected as u8, None);
+            }
+        }
+        Err(e) => {
+            log_handshake_error("socks4 request", connection.peer, &e, &config.metrics);
+            let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+            let _ = socks4::reply(client_stream, Socks4Reply::Rejected, &unspecified).await;
+        }
+    }
+}
+
+
+/// Приветствие, аутентификация и запрос SOCKS5. Ошибки уже залогированы
+/// и отвечены клиенту; `None` означает, что соединение нужно закрыть
+async fn socks5_handshake(client_stream: &mut dyn ClientStream, connection: &ConnectionStats, config: &Config) -> Option<(Option<String>, Request)> /* username, request */ {
+    let method = match process_method(client_stream, &config.auth_methods).await {
+        Ok(Some(chosen)) => chosen,
+        Ok(None) => {
+            info!("no acceptable auth methods offered");
+            return None;
+        }
+        Err(e) => {
+            log_handshake_error("method", connection.peer, &e, &config.metrics);
+            return None;
+        }
+    };
+    debug!("method: {:#04x}", method.id());
+
+    let client = ClientInfo {
+        peer_addr: client_stream.peer_addr().ok()?,
+        local_addr: client_stream.local_addr().ok()?,
+    };
+    let username = match method.negotiate(client_stream, &client).await {
+        Ok(AuthOutcome::Authenticated(username)) => {
+            if let Some(user) = &username {
+                info!("authenticated as {}", user);
+            }
+            username
+        }
+        Ok(AuthOutcome::Rejected) => {
+            warn!("authentication failed");
+            return None;
+        }
+        Err(e) => {
+            log_handshake_error("authentication", connection.peer, &e, &config.metrics);
+            return None;
+        }
+    };
+
+    match process_request(client_stream, config.dns.as_ref(), config.require_hostname, &config.blocked_domains, config.upstream.is_some()).await {
+        Ok(request) => Some((username, request)),
+        Err(e) => {
+            log_handshake_error("request", connection.peer, &e, &config.metrics);
+            let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+            let _ = reply(client_stream, error_reply(&e), &unspecified).await;
+            None
+        }
+    }
+}
+
+
+async fn handle_socks5_client(client_stream: &mut dyn ClientStream, connection: &ConnectionStats, config: &Config, handshake_deadline: tokio::time::Instant) {
+    let (username, request) = match timeout_at(handshake_deadline, socks5_handshake(client_stream, connection, config)).await {
+        Ok(Some(handshake)) => handshake,
+        Ok(None) => return,
+        Err(_) => {
+            config.metrics.connection_error();
+            info!("handshake timed out");
+            return;
+        }
+    };
+    if config.unexpected_data(client_stream) {
+        let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+        let code = SOCKSReply::GeneralSOCKSServerFailture;
+        let _ = reply(client_stream, code, &unspecified).await;
+        config.log_access(connection.peer, SOCKS5_VERSION, username.as_deref(), &request, code as u8, None);
+        return;
+    }
+    match request.command {
+        Command::Bind => {
+            if config.allow_bind {
+                handle_bind(client_stream, connection, &request, username.as_deref(), config).await;
+            } else {
+                warn!("bind is disabled");
+                let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+                let _ = reply(client_stream, SOCKSReply::CommandNotSupported, &unspecified).await;
+                config.log_access(connection.peer, SOCKS5_VERSION, username.as_deref(), &request, SOCKSReply::CommandNotSupported as u8, None);
+            }
+        }
+        Command::UdpAssociate => {
+            let status = match udp::process_udp_associate(client_stream, request.target_addr, config.udp_source_check, &config.acl, config.dns.as_ref(), config.udp_options, &config.metrics).await {
+                Ok(()) => {
+                    connection.set_succeeded();
+                    SOCKSReply::Succeeded
+                }
+                Err(e) => {
+                    config.metrics.connection_error();
+                    warn!("udp associate error: {}", e);
+                    let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+                    let code = error_reply(&e);
+                    let _ = reply(client_stream, code, &unspecified).await;
+                    code
+                }
+            };
+            info!("udp association closed");
+            config.log_access(connection.peer, SOCKS5_VERSION, username.as_deref(), &request, status as u8, None);
+        }
+        _ => {
+            handle_connect(client_stream, connection, &request, username.as_deref(), config).await;
+        }
+    }
+}
+
+
+/// Подключается к цели напрямую или через вышестоящий прокси,
+/// не дольше `connect_timeout`, если он задан. Вышестоящему прокси
+/// передаётся `name`, если он есть, с портом из `target_addr`
+async fn connect_target(target_addr: SocketAddr, name: Option<&[u8]>, config: &Config) -> Result<TcpStream> {
+    if let Some(upstream) = config.upstream {
+        let credentials = config.upstream_auth.as_ref().map(|(username, password)| (username.as_str(), password.as_str()));
+        let target = match name {
+            Some(name) => Address::Domain(name, target_addr.port()),
+            None => Address::Ip(target_addr),
+        };
+        let stream = client::socks5_connect_through(upstream, target, credentials, config.connect_timeout).await?;
+        config.tune_socket(&stream);
+        return Ok(stream);
+    }
+    let stream = match config.connect_timeout {
+        Some(connect_timeout) => match timeout(connect_timeout, connect_from(config.outbound_ip, target_addr)).await {
+            Ok(stream) => stream?,
+            // Истёкший таймаут отвечается так же, как таймаут ядра
+            Err(_) => return Err(SocksError::Timeout("connecting to the target").into()),
+        },
+        None => connect_from(config.outbound_ip, target_addr).await?,
+    };
+    config.tune_socket(&stream);
+    Ok(stream)
+}
+
+
+/// Подключается к `remote`, предварительно привязав сокет к локальному адресу
+/// `local` (порт выбирает система). Без `local` - обычный `TcpStream::connect`
+async fn connect_from(local: Option<IpAddr>, remote: SocketAddr) -> Result<TcpStream> {
+    let Some(local) = local else {
+        return Ok(TcpStream::connect(remote).await.map_err(SocksError::ConnectFailed)?);
+    };
+    // С адресом другого семейства сокет не создать, а подключаться мимо
+    // заданного адреса нельзя - пусть connect_any попробует следующий адрес
+    if local.is_ipv4() != remote.is_ipv4() {
+        anyhow::bail!("outbound address {} cannot reach {}", local, remote);
+    }
+    let socket = if local.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket.bind(SocketAddr::new(local, 0)).with_context(|| format!("Cannot bind outgoing socket to {}", local))?;
+    Ok(socket.connect(remote).await.map_err(SocksError::ConnectFailed)?)
+}
+
+
+/// Пробует адреса по порядку, пока к одному не удастся подключиться; каждая
+/// попытка ограничена `connect_timeout` отдельно. Возвращает поток, адрес,
+/// к которому подключились, и время удачной попытки, а если не вышло ни с одним -
+/// ошибку последней попытки. `name` - `Request::upstream_name`
+async fn connect_any(addrs: &[SocketAddr], name: Option<&[u8]>, config: &Config) -> Result<(TcpStream, SocketAddr, Duration)> {
+    let mut last_error = None;
+    for &addr in addrs {
+        let started = Instant::now();
+        match connect_target(addr, name, config).await {
+            Ok(stream) => return Ok((stream, addr, started.elapsed())),
+            Err(e) => {
+                debug!("connection to {} failed: {}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| SocksError::NoAddresses.into()))
+}
+
+
+/// Ретранслирует данные между клиентом и подключённой целью; ответ об успехе
+/// клиенту уже отправлен. В режиме проверки вместо ретрансляции сообщает, за сколько
+/// удалось подключиться, и закрывает оба соединения через `test_mode`.
+/// Возвращает переданные байты или `None`, если ретрансляция оборвалась с ошибкой
+async fn relay_target(target_stream: &mut TcpStream, client_stream: &mut dyn ClientStream, target_addr: SocketAddr, connect_time: Duration, connection: &ConnectionStats, config: &Config) -> Option<u64> {
+    connection.set_target(target_addr);
+    if let Some(delay) = config.test_mode {
+        info!("test: {} reachable, connect took {:.1} ms", target_addr, connect_time.as_secs_f64() * 1000.0);
+        tokio::time::sleep(delay).await;
+        connection.set_succeeded();
+        return Some(0);
+    }
+    let header = if config.proxy_protocol_v2_targets.contains(&target_addr) {
+        Some(proxy_protocol::header_v2(connection.peer, target_addr))
+    } else if config.emit_proxy_protocol {
+        Some(proxy_protocol::header_v1(connection.peer, connection.local).into_bytes())
+    } else {
+        None
+    };
+    if let Some(header) = header {
+        // write_all дописывает остаток после частичной записи
+        if let Err(e) = target_stream.write_all(&header).await {
+            config.metrics.connection_error();
+            warn!("cannot send proxy protocol header: {}", e);
+            config.reset_on_close(&*target_stream);
+            return None;
+        }
+    }
+    match relay(target_stream, client_stream, config.idle_timeout, config.rate_limit, config.buffer_size, &config.metrics, connection.bytes()).await {
+        Ok(transferred) => {
+            connection.set_succeeded();
+            Some(transferred)
+        }
+        Err(e) => {
+            config.metrics.connection_error();
+            warn!("relay error: {}", e);
+            config.reset_on_close(&*target_stream);
+            None
+        }
+    }
+}
+
+
+/// Семейство адреса для логов; имя для вышестоящего прокси резолвит он сам
+fn address_family(request: &Request, addr: &SocketAddr) -> &'static str {
+    if request.upstream_name.is_some() {
+        "upstream proxy"
+    } else if addr.is_ipv4() {
+        "IPv4"
+    } else {
+        "IPv6"
+    }
+}
+
+
+/// В ответе об успехе BND.ADDR/BND.PORT - локальный адрес исходящего соединения
+/// (RFC 1928), в ответах об ошибке - нули.
+///
+/// Адреса, запрещённые правилами, пропускаются, к остальным подключаемся по
+/// порядку до первого успеха; если запрещены все, отвечаем отказом
+async fn handle_connect(client_stream: &mut dyn ClientStream, connection: &ConnectionStats, request: &Request, username: Option<&str>, config: &Config) {
+    let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+    let target_addr = request.target_addr;
+    let allowed = config.allowed_addrs(request);
+    if allowed.is_empty() {
+        warn!("connection to {} denied by rules", target_addr);
+        let code = SocksError::PolicyDenied.reply_code();
+        let _ = reply(client_stream, code, &unspecified).await;
+        config.log_access(connection.peer, SOCKS5_VERSION, username, request, code as u8, None);
+        return;
+    }
+
+    match connect_any(&allowed, request.upstream_name.as_deref(), config).await {
+        Ok((mut target_stream, target_addr, connect_time)) => {
+            info!("connected to {} over {} (user: {})", request.describe(target_addr), address_family(request, &target_addr), username.unwrap_or("-"));
+            let bound_addr = target_stream.local_addr().unwrap_or(unspecified);
+            if reply(client_stream, SOCKSReply::Succeeded, &bound_addr).await.is_ok() {
+                let transferred = relay_target(&mut target_stream, client_stream, target_addr, connect_time, connection, config).await;
+                info!("done to {}", request.describe(target_addr));
+                config.log_access(connection.peer, SOCKS5_VERSION, username, request, SOCKSReply::Succeeded as u8, transferred);
+            }
+        }
+        Err(e) => {
+            config.metrics.connection_error();
+            warn!("connection to {} failed: {}", request.describe(target_addr), e);
+            let code = error_reply(&e);
+            let _ = reply(client_stream, code, &unspecified).await;
+            config.log_access(connection.peer, SOCKS5_VERSION, username, request, code as u8, None);
+        }
+    }
+}
+
+
+async fn handle_bind(client_stream: &mut dyn ClientStream, connection: &ConnectionStats, request: &Request, username: Option<&str>, config: &Config) {
+    match bind::process_bind(client_stream, request.target_addr, config.bind_timeout, config.bind_ports).await {
+        Ok(mut peer_stream) => {
+            config.tune_socket(&peer_stream);
+            if let Ok(peer_addr) = peer_stream.peer_addr() {
+                connection.set_target(peer_addr);
+            }
+            let transferred = match relay(&mut peer_stream, client_stream, config.idle_timeout, config.rate_limit, config.buffer_size, &config.metrics, connection.bytes()).await {
+                Ok(transferred) => Some(transferred),
+                Err(e) => {
+                    config.metrics.connection_error();
+                    warn!("relay error: {}", e);
+                    None
+                }
+            };
+            info!("bind relay done");
+            config.log_access(connection.peer, SOCKS5_VERSION, username, request, SOCKSReply::Succeeded as u8, transferred);
+        }
+        Err(e) => {
+            config.metrics.connection_error();
+            warn!("bind error: {}", e);
+            let code = error_reply(&e);
+            let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+            let _ = reply(client_stream, code, &unspecified).await;
+            config.log_access(connection.peer, SOCKS5_VERSION, username, request, code as u8, None);
+        }
+    }
+}
+
+
+/// Настройки, общие для всех обработчиков клиентов
+struct Config {
+    /// Пользователи для логина/пароля; пустой список отключает этот метод
+    users: UsernamePassword,
+    /// Методы аутентификации в порядке приоритета; окончательно
+    /// собираются в `finish_auth` перед запуском сервера
+    auth_methods: Vec<Box<dyn AuthMethod>>,
+    /// Коды включённых методов в порядке приоритета, см. `with_auth_priority`
+    auth_priority: Option<Vec<u8>>,
+    allow_bind: bool,
+    /// Сколько BIND ждёт входящего соединения
+    bind_timeout: Duration,
+    /// Порты слушающих сокетов BIND; без них порт выбирает система
+    bind_ports: Option<PortRange>,
+    connect_timeout: Option<Duration>,
+    /// Общий срок на TLS, приветствие, аутентификацию и запрос
+    handshake_timeout: Duration,
+    /// Через сколько закрывать соединение без данных в обе стороны
+    idle_timeout: Option<Duration>,
+    /// Скорость ретрансляции каждого направления соединения, байт в секунду
+    rate_limit: Option<u64>,
+    /// Размер буфера ретрансляции каждого направления
+    buffer_size: usize,
+    /// TCP_NODELAY для соединений с клиентами и целями
+    nodelay: bool,
+    /// TCP keepalive для соединений с клиентами и целями
+    keepalive: Option<Keepalive>,
+    /// Сколько после успешной сессии ждать, пока клиент закроет соединение;
+    /// с ним соединения, закрытые из-за ошибки, сбрасываются RST
+    linger: Option<Duration>,
+    /// Режим проверки: CONNECT только подключается к цели и через столько закрывается
+    test_mode: Option<Duration>,
+    /// Принимать датаграммы UDP ASSOCIATE только с адреса из запроса
+    udp_source_check: bool,
+    /// Настройки UDP ассоциации: собеседники, размер датаграмм, адрес
+    udp_options: udp::UdpOptions,
+    /// Отправлять цели CONNECT заголовок PROXY protocol v1 с адресом клиента
+    emit_proxy_protocol: bool,
+    /// Цели, которым отправляется заголовок PROXY protocol v2
+    proxy_protocol_v2_targets: TargetList,
+    /// Отказывать клиентам, приславшим данные до ответа на запрос
+    strict_handshake: bool,
+    /// Отклонять CONNECT к IP адресам, чтобы имена резолвил только сервер
+    require_hostname: bool,
+    /// Запрещённые имена целей CONNECT, проверяются до резолвинга
+    blocked_domains: DomainMatcher,
+    /// Принимать запросы HTTP CONNECT и на порту SOCKS
+    sniff_http: bool,
+    /// Читать адрес клиента из заголовка PROXY protocol от балансировщика
+    accept_proxy_protocol: bool,
+    /// Правила для адресов назначения
+    acl: AccessControl,
+    /// DNS сервер для доменов из запросов; без него используется системный резолвер
+    dns: Option<DnsResolver>,
+    /// Какое семейство адресов домена пробовать первым
+    prefer: AddressPreference,
+    /// SOCKS5 прокси, через который устанавливаются исходящие соединения
+    upstream: Option<SocketAddr>,
+    /// Имя и пароль для вышестоящего прокси
+    upstream_auth: Option<(String, String)>,
+    /// Локальный адрес прямых исходящих соединений; без него выбирает система
+    outbound_ip: Option<IpAddr>,
+    /// Если задан, клиенты подключаются по TLS
+    tls: Option<TlsAcceptor>,
+    /// Если задан, поток клиентов SOCKS порта обфусцирован этим ключом
+    obfs: Option<obfs::ObfsKey>,
+    metrics: Arc<Metrics>,
+    /// Активные соединения для `with_stats_socket`
+    stats: StatsHandle,
+    /// Сколько соединений одновременно принимается всего и с одного IP
+    conn_limit: ConnectionLimit,
+    /// Журнал обслуженных запросов
+    access_log: Option<AccessLogger>,
+}
+
+impl Config {
+    /// Добавляет логин/пароль после зарегистрированных методов, если заданы
+    /// пользователи. Если задан `auth_priority`, оставляет только перечисленные
+    /// в нём методы в его порядке; иначе без единого метода клиенты принимаются
+    /// без аутентификации
+    fn finish_auth(&mut self) {
+        if !self.users.is_empty() {
+            let users = std::mem::take(&mut self.users);
+            self.auth_methods.push(Box::new(users));
+        }
+        match &self.auth_priority {
+            Some(priority) => {
+                if priority.contains(&METHOD_NO_AUTH) && !self.allows_anonymous() {
+                    self.auth_methods.push(Box::new(NoAuth));
+                }
+                self.auth_methods.retain(|method| priority.contains(&method.id()));
+                // Сортировка устойчива: методы с одним кодом остаются в порядке регистрации
+                self.auth_methods.sort_by_key(|method| priority.iter().position(|id| *id == method.id()));
+            }
+            None if self.auth_methods.is_empty() => self.auth_methods.push(Box::new(NoAuth)),
+            None => {}
+        }
+    }
+
+    /// Адрес клиента и адрес, к которому он подключился: из заголовка PROXY
+    /// protocol, если он включён, иначе адреса сокета. `None`, если
+    /// заголовок некорректен и соединение нужно закрыть
+    async fn client_addrs(&self, stream: &mut TcpStream, peer: SocketAddr, local: SocketAddr) -> Option<(SocketAddr, SocketAddr)> {
+        if !self.accept_proxy_protocol {
+            return Some((peer, local));
+        }
+        match timeout(self.handshake_timeout, proxy_protocol::read_header(stream)).await {
+            Ok(Ok(Some(addrs))) => Some(addrs),
+            Ok(Ok(None)) => Some((peer, local)),
+            Ok(Err(e)) => {
+                self.metrics.connection_error();
+                warn!("invalid proxy protocol header from {}: {}", peer, e);
+                None
+            }
+            Err(_) => {
+                self.metrics.connection_error();
+                info!("handshake timed out (proxy protocol header from {})", peer);
+                None
+            }
+        }
+    }
+
+    /// В строгом режиме проверяет, не прислал ли клиент что-то после запроса,
+    /// не дождавшись ответа. Видны только данные, уже прочитанные в буфер
+    fn unexpected_data(&self, client_stream: &dyn ClientStream) -> bool {
+        if !self.strict_handshake {
+            return false;
+        }
+        let unexpected = client_stream.buffered();
+        if unexpected == 0 {
+            return false;
+        }
+        self.metrics.connection_error();
+        warn!("strict handshake: {} unexpected bytes after the request", unexpected);
+        true
+    }
+
+    /// Настраивает сокет клиента или цели: TCP_NODELAY и keepalive. Без них
+    /// соединение работает как раньше, поэтому ошибки не фатальны
+    fn tune_socket(&self, stream: &TcpStream) {
+        if self.nodelay {
+            if let Err(e) = stream.set_nodelay(true) {
+                debug!("cannot set tcp nodelay: {}", e);
+            }
+        }
+        if let Some(keepalive) = &self.keepalive {
+            if let Err(e) = keepalive.apply(stream) {
+                debug!("cannot enable tcp keepalive: {}", e);
+            }
+        }
+    }
+
+    /// С `linger` соединение после ошибки закрывается RST без TIME_WAIT:
+    /// SO_LINGER с нулевым таймаутом. Данные, которые ещё не ушли, теряются
+    fn reset_on_close(&self, stream: &dyn ClientStream) {
+        if self.linger.is_some() {
+            if let Err(e) = stream.set_linger(Some(Duration::ZERO)) {
+                debug!("cannot set so_linger: {}", e);
+            }
+        }
+    }
+
+    /// Доступен ли метод без аутентификации; иначе SOCKS4 клиенты не допускаются
+    fn allows_anonymous(&self) -> bool {
+        self.auth_methods.iter().any(|method| method.id() == METHOD_NO_AUTH)
+    }
+
+    /// Адреса цели, разрешённые правилами, в порядке попыток. Имя для
+    /// вышестоящего прокси не резолвится, и правила по IP к нему не применяются
+    fn allowed_addrs(&self, request: &Request) -> Vec<SocketAddr> {
+        if request.upstream_name.is_some() {
+            return vec![request.target_addr];
+        }
+        let mut allowed: Vec<SocketAddr> = request.addrs().filter(|addr| self.acl.is_allowed(addr)).collect();
+        self.prefer.order(&mut allowed);
+        allowed
+    }
+
+    /// Записывает обслуженный запрос SOCKS в журнал доступа, если он включён.
+    /// `status` - код, отправленный клиенту, `transferred` - байт ретранслировано
+    fn log_access(&self, peer: SocketAddr, version: u8, username: Option<&str>, request: &Request, status: u8, transferred: Option<u64>) {
+        let protocol = if version == SOCKS4_VERSION { "SOCKS4" } else { "SOCKS5" };
+        self.log_access_as(protocol, peer, username, request, status.into(), transferred);
+    }
+
+    fn log_access_as(&self, protocol: &'static str, peer: SocketAddr, username: Option<&str>, request: &Request, status: u16, transferred: Option<u64>) {
+        let Some(access_log) = &self.access_log else {
+            return;
+        };
+        access_log.log(LogEntry {
+            client: peer.ip(),
+            username: username.map(str::to_owned),
+            time: std::time::SystemTime::now(),
+            command: request.command.name(),
+            target: request.target_addr,
+            host: request.upstream_name.as_deref().map(|name| String::from_utf8_lossy(name).into_owned()),
+            protocol,
+            status,
+            bytes: transferred,
+        });
+    }
+}
+
+
+/// Сколько после запроса остановки ждать завершения активных соединений
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+/// SOCKS-сервер: принимает клиентов на `bind_addr` и обслуживает каждого
+/// в отдельной задаче tokio
+pub struct SocksServer {
+    bind_addr: SocketAddr,
+    config: Config,
+    shutdown: Option<Arc<Shutdown>>,
+    /// Адрес дополнительного слушателя HTTP CONNECT
+    http_proxy_addr: Option<SocketAddr>,
+    /// Адрес HTTP страницы `/metrics`; без него метрики только собираются
+    metrics_addr: Option<SocketAddr>,
+    /// Unix сокет со снимком активных соединений в JSON
+    stats_socket: Option<PathBuf>,
+    /// Дополнительный слушатель SOCKS на Unix сокете
+    unix_socket: Option<PathBuf>,
+    /// Права файла `unix_socket`; без них - по umask
+    unix_socket_mode: Option<u32>,
+    /// Владелец и группа файла `unix_socket`
+    unix_socket_owner: (Option<u32>, Option<u32>),
+    /// SO_REUSEPORT на слушающем сокете
+    reuse_port: bool,
+    /// Готовый слушающий сокет вместо `bind` на `bind_addr`
+    listener: Option<std::net::TcpListener>,
+    /// Куда сообщить, что все слушатели открыты
+    ready: Option<std::sync::mpsc::Sender<()>>,
+}
+
+impl SocksServer {
+    pub fn new(bind_addr: SocketAddr) -> SocksServer {
+        SocksServer {
+            bind_addr,
+            config: Config {
+                users: UsernamePassword::new(),
+                auth_methods: Vec::new(),
+                auth_priority: None,
+                allow_bind: false,
+                bind_timeout: bind::DEFAULT_ACCEPT_TIMEOUT,
+                bind_ports: None,
+                connect_timeout: None,
+                handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+                idle_timeout: None,
+                rate_limit: None,
+                buffer_size: DEFAULT_BUFFER_SIZE,
+                test_mode: None,
+                nodelay: true,
+                keepalive: None,
+                linger: None,
+                udp_source_check: true,
+                udp_options: udp::UdpOptions::default(),
+                emit_proxy_protocol: false,
+                proxy_protocol_v2_targets: TargetList::new(),
+                strict_handshake: false,
+                require_hostname: false,
+                blocked_domains: DomainMatcher::new(),
+                sniff_http: false,
+                accept_proxy_protocol: false,
+                acl: AccessControl::new(),
+                dns: None,
+                prefer: AddressPreference::Auto,
+                upstream: None,
+                upstream_auth: None,
+                outbound_ip: None,
+                tls: None,
+                obfs: None,
+                metrics: Arc::default(),
+                stats: StatsHandle::default(),
+                conn_limit: ConnectionLimit::default(),
+                access_log: None,
+            },
+            shutdown: None,
+            http_proxy_addr: None,
+            metrics_addr: None,
+            stats_socket: None,
+            unix_socket: None,
+            unix_socket_mode: None,
+            unix_socket_owner: (None, None),
+            listener: None,
+            ready: None,
+            reuse_port: false,
+        }
+    }
+
+    /// Добавляет пользователя и включает аутентификацию по логину/паролю (RFC 1929)
+    pub fn with_auth(mut self, username: impl Into<String>, password: impl Into<String>) -> SocksServer {
+        self.config.users.add_user(username, password);
+        self
+    }
+
+    /// Регистрирует метод аутентификации. Если клиент предлагает несколько
+    /// методов, выбирается зарегистрированный раньше; логин/пароль из `with_auth`
+    /// идёт после всех зарегистрированных. Без аутентификации клиенты
+    /// принимаются, только если ни одного метода не задано или зарегистрирован `NoAuth`
+    pub fn with_auth_method(mut self, method: Box<dyn AuthMethod>) -> SocksServer {
+        self.config.auth_methods.push(method);
+        self
+    }
+
+    /// Явно задаёт включённые методы аутентификации и их приоритет по кодам METHOD:
+    /// из предложенных клиентом выбирается метод, стоящий в `priority` раньше.
+    /// Методы, которых нет в списке, отключаются; `METHOD_NO_AUTH` в списке
+    /// разрешает анонимный доступ даже при настроенных пользователях
+    pub fn with_auth_priority(mut self, priority: Vec<u8>) -> SocksServer {
+        self.config.auth_priority = Some(priority);
+        self
+    }
+
+    /// Разрешает команду BIND (нужна, например, для активного режима FTP)
+    pub fn with_bind(mut self, allow_bind: bool) -> SocksServer {
+        self.config.allow_bind = allow_bind;
+        self
+    }
+
+    /// Сколько BIND ждёт входящего соединения; по истечении клиент получает
+    /// второй ответ TTL expired. По умолчанию `DEFAULT_ACCEPT_TIMEOUT`
+    pub fn with_bind_timeout(mut self, timeout: Duration) -> SocksServer {
+        assert!(!timeout.is_zero(), "Bind timeout must be positive");
+        self.config.bind_timeout = timeout;
+        self
+    }
+
+    /// Порты, на которых BIND может слушать, например открытые в файрволе.
+    /// Когда все заняты, клиент получает общую ошибку
+    pub fn with_bind_port_range(mut self, ports: PortRange) -> SocksServer {
+        self.config.bind_ports = Some(ports);
+        self
+    }
+
+    /// Ограничивает время установки соединения с целью
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> SocksServer {
+        self.config.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Сколько ждать данных от клиента при разборе приветствия, аутентификации и запроса
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> SocksServer {
+        assert!(!timeout.is_zero(), "Handshake timeout must be positive");
+        self.config.handshake_timeout = timeout;
+        self
+    }
+
+    /// Закрывает ретранслируемые соединения, простаивающие дольше `timeout`
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> SocksServer {
+        self.config.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Ограничивает скорость ретрансляции: не больше `bytes_per_second`
+    /// в каждую сторону для каждого соединения
+    pub fn with_rate_limit(mut self, bytes_per_second: u64) -> SocksServer {
+        assert!(bytes_per_second > 0, "Rate limit must be positive");
+        self.config.rate_limit = Some(bytes_per_second);
+        self
+    }
+
+    /// Размер буфера ретрансляции каждого направления соединения: больший
+    /// буфер уменьшает число системных вызовов на быстрых соединениях ценой
+    /// памяти. Степень двойки из `BUFFER_SIZE_RANGE`
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> SocksServer {
+        assert!(
+            buffer_size.is_power_of_two() && BUFFER_SIZE_RANGE.contains(&buffer_size),
+            "Buffer size must be a power of two between 512 and 65536"
+        );
+        self.config.buffer_size = buffer_size;
+        self
+    }
+
+    /// Отключает алгоритм Нейгла (TCP_NODELAY) на соединениях с клиентами и
+    /// целями, чтобы мелкие записи интерактивных протоколов (SSH, запросы к
+    /// БД) уходили сразу. Включено по умолчанию; для массовой передачи
+    /// данных можно выключить
+    pub fn with_nodelay(mut self, nodelay: bool) -> SocksServer {
+        self.config.nodelay = nodelay;
+        self
+    }
+
+    /// Включает TCP keepalive на соединениях с клиентами и целями, чтобы
+    /// долго простаивающие туннели не разрывались NAT
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> SocksServer {
+        keepalive.warn_unsupported();
+        self.config.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Соединения, закрытые из-за ошибки (цель недоступна, запрос отклонён,
+    /// ретрансляция оборвалась), сбрасываются RST, чтобы под нагрузкой
+    /// TIME_WAIT не исчерпал эфемерные порты. После успешной сессии прокси
+    /// ждёт до `linger`, пока клиент дочитает данные и закроет соединение.
+    /// SO_LINGER с ненулевым таймаутом не используется: с ним close()
+    /// блокирует поток рантайма
+    pub fn with_linger(mut self, linger: Duration) -> SocksServer {
+        self.config.linger = Some(linger);
+        self
+    }
+
+    /// Режим проверки правил и доступности целей: CONNECT подключается к цели,
+    /// отвечает клиенту об успехе, логирует время подключения и через `delay`
+    /// закрывает оба соединения, не передавая данных
+    pub fn with_test_mode(mut self, delay: Duration) -> SocksServer {
+        self.config.test_mode = Some(delay);
+        self
+    }
+
+    /// Задаёт правила, по которым разрешаются адреса назначения
+    pub fn with_access_control(mut self, acl: AccessControl) -> SocksServer {
+        self.config.acl = acl;
+        self
+    }
+
+    /// Резолвит домены из запросов через DNS сервер `server` по TCP,
+    /// не обращаясь к системному резолверу
+    pub fn with_dns(mut self, server: SocketAddr) -> SocksServer {
+        self.config.dns = Some(DnsResolver::new(server));
+        self
+    }
+
+    /// Задаёт, какое семейство адресов пробовать первым, если домен
+    /// резолвится и в IPv4, и в IPv6
+    pub fn with_address_preference(mut self, prefer: AddressPreference) -> SocksServer {
+        self.config.prefer = prefer;
+        self
+    }
+
+    /// Устанавливает исходящие TCP соединения через SOCKS5 прокси `upstream`
+    /// (например, Tor) вместо прямого подключения
+    pub fn with_upstream(mut self, upstream: SocketAddr) -> SocksServer {
+        self.config.upstream = Some(upstream);
+        self
+    }
+
+    /// Имя и пароль (RFC 1929) для вышестоящего прокси из `with_upstream`.
+    /// Отказ прокси клиент получает как общую ошибку сервера
+    pub fn with_upstream_auth(mut self, username: impl Into<String>, password: impl Into<String>) -> SocksServer {
+        let username = username.into();
+        let password = password.into();
+        assert!(
+            (1..=255).contains(&username.len()) && (1..=255).contains(&password.len()),
+            "Username and password must be 1 to 255 bytes long"
+        );
+        self.config.upstream_auth = Some((username, password));
+        self
+    }
+
+    /// Принимать ли датаграммы UDP ASSOCIATE только с адреса, указанного
+    /// клиентом в запросе (RFC 1928). Включено по умолчанию; без проверки
+    /// принимаются датаграммы с IP клиента с любого порта - для клиентов,
+    /// которые указывают в запросе неверный адрес, например за NAT
+    pub fn with_udp_source_check(mut self, check: bool) -> SocksServer {
+        self.config.udp_source_check = check;
+        self
+    }
+
+    /// Сколько UDP ассоциация помнит адрес, которому писал клиент, и сколько
+    /// таких адресов у неё может быть (ответы принимаются только от
+    /// запомненных), наибольший размер датаграммы с заголовком SOCKS и адрес
+    /// ретранслятора: внешний IP для ответа клиенту и диапазон портов.
+    /// По умолчанию 60 секунд, 1024 адреса, 65535 байт, адрес сокета
+    pub fn with_udp_options(mut self, options: udp::UdpOptions) -> SocksServer {
+        self.config.udp_options = options;
+        self
+    }
+
+    /// Перед данными клиента отправлять цели CONNECT заголовок PROXY protocol v1
+    /// (`PROXY TCP4 <client_ip> <server_ip> <client_port> <server_port>`), чтобы
+    /// HAProxy или Nginx за прокси видели настоящий адрес клиента
+    pub fn with_emit_proxy_protocol(mut self, emit: bool) -> SocksServer {
+        self.config.emit_proxy_protocol = emit;
+        self
+    }
+
+    /// Отправлять целям CONNECT из `targets` двоичный заголовок PROXY protocol v2
+    /// с адресом клиента и адресом назначения. Для этих целей он заменяет
+    /// заголовок v1 из `with_emit_proxy_protocol`; остальные цели его не получают
+    pub fn with_proxy_protocol_v2_targets(mut self, targets: TargetList) -> SocksServer {
+        self.config.proxy_protocol_v2_targets = targets;
+        self
+    }
+
+    /// Отказывать клиентам, которые прислали данные после запроса, не
+    /// дождавшись ответа. По умолчанию такие данные считаются началом
+    /// ретранслируемого потока; строгий режим помогает найти неисправные
+    /// клиентские библиотеки
+    pub fn with_strict_handshake(mut self, strict: bool) -> SocksServer {
+        self.config.strict_handshake = strict;
+        self
+    }
+
+    /// Принимать CONNECT только к доменным именам, как SafeSocks в Tor: имена
+    /// резолвит сервер, и DNS запросы клиента не утекают в локальную сеть.
+    /// Запросы с IP адресом (SOCKS5 ATYP 0x01/0x04, SOCKS4 без 4a, HTTP)
+    /// получают отказ, как запрещённые правилами
+    pub fn with_require_hostname(mut self, require: bool) -> SocksServer {
+        self.config.require_hostname = require;
+        self
+    }
+
+    /// Отказывать в CONNECT к именам из `blocked` (SOCKS5, SOCKS4a и HTTP)
+    /// с ответом 0x02, не резолвя их. IP адреса сверяются в текстовом виде
+    pub fn with_blocked_domains(mut self, blocked: DomainMatcher) -> SocksServer {
+        self.config.blocked_domains = blocked;
+        self
+    }
+
+    /// Принимать на порту SOCKS и запросы HTTP CONNECT: протокол выбирается
+    /// по первому байту соединения, прочитанному в общий буфер. Без этого
+    /// HTTP клиенты на порту SOCKS не получают ответа
+    pub fn with_http_sniffing(mut self, sniff: bool) -> SocksServer {
+        self.config.sniff_http = sniff;
+        self
+    }
+
+    /// Ожидать перед приветствием заголовок PROXY protocol v1 или v2 от
+    /// балансировщика (HAProxy, Nginx) и считать адресом клиента адрес из
+    /// него: по нему работают лимит соединений, журналы и статистика.
+    /// Соединения без корректного заголовка закрываются
+    pub fn with_accept_proxy_protocol(mut self, accept: bool) -> SocksServer {
+        self.config.accept_proxy_protocol = accept;
+        self
+    }
+
+    /// Привязывает прямые исходящие TCP соединения к локальному адресу `ip`,
+    /// например на сервере с несколькими внешними адресами. Цели другого
+    /// семейства адресов становятся недоступны
+    pub fn with_outbound_ip(mut self, ip: IpAddr) -> SocksServer {
+        self.config.outbound_ip = Some(ip);
+        self
+    }
+
+    /// Скрывает SOCKS от DPI: весь поток клиентов SOCKS порта XOR с `key`,
+    /// клиент должен делать то же (`client::Socks5Stream::connect_obfuscated`).
+    /// Это не шифрование; вместе с TLS не используется
+    pub fn with_obfuscation(mut self, key: obfs::ObfsKey) -> SocksServer {
+        self.config.obfs = Some(key);
+        self
+    }
+
+    /// Принимает клиентов только по TLS (SOCKS поверх TLS), см. `tls::load_server_config`
+    pub fn with_tls(mut self, tls: Arc<rustls::ServerConfig>) -> SocksServer {
+        self.config.tls = Some(TlsAcceptor::from(tls));
+        self
+    }
+
+    /// Отдаёт метрики в формате Prometheus по HTTP на `addr`
+    pub fn with_metrics_addr(mut self, addr: SocketAddr) -> SocksServer {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Использует общие с другими серверами счётчики вместо собственных
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> SocksServer {
+        self.config.metrics = metrics;
+        self
+    }
+
+    /// Отдаёт снимок активных соединений (клиент, цель, переданные байты,
+    /// время начала) в JSON каждому, кто подключится к Unix сокету `path`
+    pub fn with_stats_socket(mut self, path: impl Into<PathBuf>) -> SocksServer {
+        self.stats_socket = Some(path.into());
+        self
+    }
+
+    /// Принимает клиентов SOCKS (и HTTP CONNECT с `with_http_sniffing`) ещё и
+    /// на Unix сокете `path` (только Unix), без TLS и PROXY protocol. Доступ
+    /// ограничивается правами файла, см. `with_unix_socket_permissions`
+    pub fn with_unix_socket(mut self, path: impl Into<PathBuf>) -> SocksServer {
+        self.unix_socket = Some(path.into());
+        self
+    }
+
+    /// Права (`0o660`) и числовые владелец и группа файла Unix сокета
+    pub fn with_unix_socket_permissions(mut self, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>) -> SocksServer {
+        self.unix_socket_mode = mode;
+        self.unix_socket_owner = (uid, gid);
+        self
+    }
+
+    /// Использует общий с другими серверами список соединений вместо собственного
+    pub fn with_stats(mut self, stats: StatsHandle) -> SocksServer {
+        self.config.stats = stats;
+        self
+    }
+
+    /// Ограничивает число одновременных соединений, всего и с одного IP
+    /// клиента (SOCKS и HTTP вместе). Соединение сверх лимита принимается,
+    /// клиент SOCKS получает отказ 0x02, и соединение закрывается. Серверы
+    /// с клонами одного лимита считают соединения вместе
+    pub fn with_connection_limit(mut self, limit: ConnectionLimit) -> SocksServer {
+        self.config.conn_limit = limit;
+        self
+    }
+
+    /// Принимает на `addr` клиентов HTTP прокси: запрос CONNECT обслуживается
+    /// так же, как CONNECT SOCKS5, с теми же правилами и ограничениями
+    pub fn with_http_proxy(mut self, addr: SocketAddr) -> SocksServer {
+        self.http_proxy_addr = Some(addr);
+        self
+    }
+
+    /// Пишет строку в журнал доступа о каждом обслуженном запросе
+    pub fn with_access_log(mut self, access_log: AccessLogger) -> SocksServer {
+        self.config.access_log = Some(access_log);
+        self
+    }
+
+    /// Включает SO_REUSEPORT: несколько серверов (в том числе в разных процессах)
+    /// слушают один порт, а ядро распределяет между ними входящие соединения.
+    /// Работает на Linux и macOS; на других платформах флаг игнорируется
+    pub fn with_reuse_port(mut self, reuse_port: bool) -> SocksServer {
+        self.reuse_port = reuse_port;
+        self
+    }
+
+    /// Принимает клиентов на уже открытом слушающем сокете (например, от
+    /// systemd, см. `systemd::listen_fds`) вместо того, чтобы открывать свой на
+    /// `bind_addr`. Сокет должен быть неблокирующим
+    pub fn with_listener(mut self, listener: std::net::TcpListener) -> SocksServer {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Отправляет `()` в `ready`, когда все слушатели открыты и сервер начинает
+    /// принимать соединения. Если запуск не удался, отправитель просто удаляется
+    pub fn with_ready_signal(mut self, ready: std::sync::mpsc::Sender<()>) -> SocksServer {
+        self.ready = Some(ready);
+        self
+    }
+
+    /// Счётчики сервера, например для встраивания в собственный экспорт метрик
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.config.metrics)
+    }
+
+    /// Останавливает `run` по запросу `shutdown`, например из обработчика сигналов
+    pub fn with_shutdown(mut self, shutdown: Arc<Shutdown>) -> SocksServer {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Принимает клиентов до запроса остановки, затем ждёт завершения
+    /// активных соединений не дольше `SHUTDOWN_TIMEOUT`. Должен выполняться
+    /// внутри рантайма tokio; его потоки и обслуживают клиентов
+    pub async fn run(self) -> Result<()> {
+        let listener = match self.listener {
+            Some(listener) => TcpListener::from_std(listener)?,
+            None => bind_listener(self.bind_addr, self.reuse_port).await?,
+        };
+        let http_listener = match self.http_proxy_addr {
+            Some(addr) => Some(bind_listener(addr, self.reuse_port).await?),
+            None => None,
+        };
+        let shutdown = self.shutdown.unwrap_or_else(Shutdown::new);
+        let mut config = self.config;
+        config.finish_auth();
+        let config = Arc::new(config);
+        if let Some(metrics_addr) = self.metrics_addr {
+            let metrics_listener = TcpListener::bind(metrics_addr).await?;
+            tokio::spawn(metrics::serve(metrics_listener, Arc::clone(&config.metrics)));
+            info!("Serving metrics on http://{}/metrics", metrics_addr);
+        }
+        if let Some(path) = &self.stats_socket {
+            stats::listen(path, config.stats.clone())
+                .with_context(|| format!("Cannot listen on stats socket {}", path.display()))?;
+            info!("Serving connection stats on {}", path.display());
+        }
+        let unix_listener = match &self.unix_socket {
+            Some(path) => {
+                let (uid, gid) = self.unix_socket_owner;
+                let listener = UnixSocketListener::bind(path, self.unix_socket_mode, uid, gid)
+                    .with_context(|| format!("Cannot listen on Unix socket {}", path.display()))?;
+                info!("Listening on {}", path.display());
+                Some(listener)
+            }
+            None => None,
+        };
+        info!("Listening on {}", listener.local_addr().unwrap_or(self.bind_addr));
+        if let Some(addr) = self.http_proxy_addr {
+            info!("Listening for HTTP CONNECT on {}", addr);
+        }
+        if let Some(ready) = self.ready {
+            let _ = ready.send(());
+        }
+
+        let mut connections = JoinSet::new();
+        loop {
+            tokio::select! {
+                _ = shutdown.requested() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok((client_stream, peer)) => {
+                        let local = client_stream.local_addr().unwrap_or(self.bind_addr);
+                        connections.spawn(serve_socks(client_stream, peer, local, Arc::clone(&config)));
+                    }
+                    Err(e) => warn!("accept error: {}", e),
+                },
+                accepted = accept_optional(http_listener.as_ref()) => match accepted {
+                    Ok((client_stream, peer)) => {
+                        let local = client_stream.local_addr().unwrap_or(self.http_proxy_addr.unwrap_or(self.bind_addr));
+                        connections.spawn(serve_http(client_stream, peer, local, Arc::clone(&config)));
+                    }
+                    Err(e) => warn!("accept error: {}", e),
+                },
+                accepted = unix_socket::accept_optional(unix_listener.as_ref()) => match accepted {
+                    Ok(client_stream) => {
+                        connections.spawn(serve_unix(client_stream, Arc::clone(&config)));
+                    }
+                    Err(e) => warn!("accept error: {}", e),
+                },
+                // Забираем завершённые задачи, чтобы набор не рос
+                Some(finished) = connections.join_next() => log_panic(finished),
+            }
+        }
+
+        drop(listener);
+        drop(http_listener);
+        // Удаляет файл сокета
+        drop(unix_listener);
+        info!("Waiting for active connections to finish...");
+        let drain = async {
+            while let Some(finished) = connections.join_next().await {
+                log_panic(finished);
+            }
+        };
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, drain).await.is_err() {
+            warn!("{} connections still active after {:?}, dropping them", connections.len(), SHUTDOWN_TIMEOUT);
+            connections.shutdown().await;
+        }
+        if let Some(path) = &self.stats_socket {
+            let _ = std::fs::remove_file(path);
+        }
+        info!("Server stopped");
+        Ok(())
+    }
+}
+
+
+async fn bind_listener(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    if reuse_port {
+        bind_reuse_port(addr)
+    } else {
+        TcpListener::bind(addr).await
+    }
+}
+
+/// Принимает соединение, если слушатель задан; иначе никогда не завершается
+async fn accept_optional(listener: Option<&TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Слушающий сокет с SO_REUSEPORT. Опция ставится до `bind`, иначе второй
+/// сервер на том же порту получит EADDRINUSE
+#[cfg(unix)]
+fn bind_reuse_port(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(not(unix))]
+fn bind_reuse_port(addr: SocketAddr) -> io::Result<TcpListener> {
+    warn!("SO_REUSEPORT is not supported on this platform, binding without it");
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+
+/// Паника в обработчике завершает только его задачу
+fn log_panic(finished: Result<(), JoinError>) {
+    if let Err(e) = finished {
+        if e.is_panic() {
+            tracing::error!("client handler panicked");
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_from_byte() {
+        assert_eq!(Command::try_from(0x01).unwrap(), Command::Connect);
+        assert_eq!(Command::try_from(0x02).unwrap(), Command::Bind);
+        assert_eq!(Command::try_from(0x03).unwrap(), Command::UdpAssociate);
+        for byte in [0x00, 0x04, 0xFF] {
+            assert!(matches!(Command::try_from(byte), Err(SocksError::UnsupportedCommand)));
+        }
+    }
+
+    #[test]
+    fn address_type_from_byte() {
+        assert_eq!(AddressType::try_from(0x01).unwrap(), AddressType::V4);
+        assert_eq!(AddressType::try_from(0x03).unwrap(), AddressType::Domain);
+        assert_eq!(AddressType::try_from(0x04).unwrap(), AddressType::V6);
+        for byte in [0x00, 0x02, 0x05] {
+            assert!(matches!(AddressType::try_from(byte), Err(SocksError::UnsupportedAddressType)));
+        }
+    }
+
+    #[test]
+    fn reply_from_io_error() {
+        let code = |kind| SOCKSReply::from_io_error(&io::Error::from(kind)) as u8;
+        assert_eq!(code(io::ErrorKind::ConnectionRefused), 0x05);
+        assert_eq!(code(io::ErrorKind::HostUnreachable), 0x04);
+        assert_eq!(code(io::ErrorKind::AddrNotAvailable), 0x04);
+        assert_eq!(code(io::ErrorKind::NetworkUnreachable), 0x03);
+        assert_eq!(code(io::ErrorKind::NetworkDown), 0x03);
+        assert_eq!(code(io::ErrorKind::TimedOut), 0x06);
+        assert_eq!(code(io::ErrorKind::PermissionDenied), 0x02);
+        assert_eq!(code(io::ErrorKind::Other), 0x01);
+        assert_eq!(SOCKSReply::from_io_error(&io::Error::from_raw_os_error(os_error::EHOSTDOWN)) as u8, 0x04);
+    }
+
+    #[test]
+    fn error_reply_for_connect_failures() {
+        let refused = anyhow::Error::from(SocksError::ConnectFailed(io::Error::from(io::ErrorKind::ConnectionRefused)));
+        assert_eq!(error_reply(&refused) as u8, 0x05);
+        let timed_out = anyhow::Error::from(SocksError::Timeout("connecting"));
+        assert_eq!(error_reply(&timed_out) as u8, 0x06);
+        let unreachable = anyhow::Error::from(io::Error::from(io::ErrorKind::NetworkUnreachable));
+        assert_eq!(error_reply(&unreachable) as u8, 0x03);
+        assert_eq!(error_reply(&anyhow::anyhow!("something else")) as u8, 0x01);
+    }
+
+    #[test]
+    fn socket_addr_layout() {
+        let mut buf = Vec::new();
+        push_socket_addr(&mut buf, &"192.0.2.1:1080".parse().unwrap());
+        assert_eq!(buf, [0x01, 192, 0, 2, 1, 0x04, 0x38]);
+
+        let mut buf = Vec::new();
+        push_socket_addr(&mut buf, &"[2001:db8::1]:443".parse().unwrap());
+        let mut expected = vec![0x04];
+        expected.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        expected.extend_from_slice(&[0x01, 0xbb]);
+        assert_eq!(buf, expected);
+    }
+
+    #[tokio::test]
+    async fn reply_layout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        reply(&mut server, SOCKSReply::ConnectionRefused, &"[::1]:80".parse().unwrap()).await.unwrap();
+        drop(server);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        let mut expected = vec![0x05, 0x05, 0x00, 0x04];
+        expected.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        expected.extend_from_slice(&[0x00, 0x50]);
+        assert_eq!(received, expected);
+    }
+
+    fn auth_ids(server: SocksServer) -> Vec<u8> {
+        let mut config = server.config;
+        config.finish_auth();
+        config.auth_methods.iter().map(|method| method.id()).collect()
+    }
+
+    #[test]
+    fn auth_methods_default() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        assert_eq!(auth_ids(SocksServer::new(addr)), [METHOD_NO_AUTH]);
+        assert_eq!(auth_ids(SocksServer::new(addr).with_auth("user", "pass")), [METHOD_USERNAME_PASSWORD]);
+    }
+
+    #[test]
+    fn auth_methods_priority() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server = SocksServer::new(addr).with_auth("user", "pass").with_auth_priority(vec![METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD]);
+        assert_eq!(auth_ids(server), [METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD]);
+        let server = SocksServer::new(addr).with_auth_method(Box::new(NoAuth)).with_auth("user", "pass").with_auth_priority(vec![METHOD_USERNAME_PASSWORD]);
+        assert_eq!(auth_ids(server), [METHOD_USERNAME_PASSWORD]);
+    }
+}
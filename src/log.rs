@@ -0,0 +1,154 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use tracing::warn;
+
+
+/// Запись журнала доступа об одном обслуженном запросе
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub client: IpAddr,
+    /// Имя пользователя, если клиент аутентифицировался
+    pub username: Option<String>,
+    /// Время завершения соединения
+    pub time: SystemTime,
+    /// CONNECT, BIND или UDP_ASSOCIATE
+    pub command: &'static str,
+    pub target: SocketAddr,
+    /// Имя цели, переданное вышестоящему прокси без резолвинга: пишется
+    /// вместо адреса из `target`, у которого известен только порт
+    pub host: Option<String>,
+    /// SOCKS4, SOCKS5 или HTTP
+    pub protocol: &'static str,
+    /// Код ответа клиенту (REP в SOCKS5, CD в SOCKS4, статус HTTP)
+    pub status: u16,
+    /// Байт передано в обе стороны; неизвестно, если ретрансляция не состоялась
+    pub bytes: Option<u64>,
+}
+
+/// Строка в Common Log Format:
+/// `client - user [10/Oct/2026:13:55:36 +0000] "CONNECT host:port SOCKS5" status bytes`
+impl fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let target = match &self.host {
+            Some(host) => format!("{}:{}", host, self.target.port()),
+            None => self.target.to_string(),
+        };
+        write!(
+            f,
+            "{} - {} [{}] \"{} {} {}\" {} ",
+            self.client,
+            self.username.as_deref().unwrap_or("-"),
+            Timestamp(self.time),
+            self.command,
+            target,
+            self.protocol,
+            self.status,
+        )?;
+        match self.bytes {
+            Some(bytes) => write!(f, "{}", bytes),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+
+/// Время в формате CLF, всегда в UTC
+struct Timestamp(SystemTime);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+        let secs = self.0.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        let (year, month, day) = civil_from_days((secs / 86400) as i64);
+        let time = secs % 86400;
+        write!(
+            f,
+            "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+            day, MONTHS[month as usize - 1], year, time / 3600, time / 60 % 60, time % 60
+        )
+    }
+}
+
+/// Дата (год, месяц, день) по числу дней от 1970-01-01 в григорианском календаре
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+
+/// Журнал доступа. Обработчики только отправляют записи в канал, а пишет их
+/// в файл отдельный поток, поэтому медленный диск не задерживает клиентов.
+/// Клонируется для каждого воркера; поток завершается вместе с последним клоном
+#[derive(Clone)]
+pub struct AccessLogger {
+    sender: Sender<LogEntry>,
+    reopen: Arc<AtomicBool>,
+}
+
+impl AccessLogger {
+    /// Открывает файл на дозапись и запускает поток записи
+    pub fn open(path: impl Into<PathBuf>) -> Result<AccessLogger> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        let (sender, receiver) = mpsc::channel();
+        let reopen = Arc::new(AtomicBool::new(false));
+        let writer_reopen = Arc::clone(&reopen);
+        thread::Builder::new()
+            .name("access-log".into())
+            .spawn(move || write_entries(receiver, path, file, &writer_reopen))
+            .context("Cannot start access log thread")?;
+        Ok(AccessLogger { sender, reopen })
+    }
+
+    pub fn log(&self, entry: LogEntry) {
+        // Ошибка только если поток записи завершился, тогда писать уже некуда
+        let _ = self.sender.send(entry);
+    }
+
+    /// Переоткрывает файл перед следующей записью, например после ротации
+    pub fn reopen(&self) {
+        self.reopen.store(true, Ordering::Relaxed);
+    }
+}
+
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Cannot open access log {}", path.display()))
+}
+
+fn write_entries(receiver: Receiver<LogEntry>, path: PathBuf, mut file: File, reopen: &AtomicBool) {
+    for entry in receiver {
+        if reopen.swap(false, Ordering::Relaxed) {
+            // Если открыть заново не удалось, продолжаем писать в старый файл
+            match open_append(&path) {
+                Ok(reopened) => file = reopened,
+                Err(e) => warn!("{:#}", e),
+            }
+        }
+        // Строка пишется одним вызовом, чтобы не перемешаться с чужими записями в файл
+        if let Err(e) = file.write_all(format!("{}\n", entry).as_bytes()) {
+            warn!("access log write failed: {}", e);
+        }
+    }
+}
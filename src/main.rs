@@ -1,389 +1,170 @@
-use std::collections::HashMap;
-use std::net::{TcpListener, TcpStream, SocketAddr, IpAddr, Ipv4Addr};
-use std::io::{Read, Write, self};
-use std::net::ToSocketAddrs;
+mod config;
+mod daemon;
+
+use std::any::Any;
+use std::io::IsTerminal;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc};
+use std::thread;
 use std::time::Duration;
-use anyhow::{Result, Error};
-use request_errors::CommandNotAllowedError;
-use polling::{Event, Poller, Events};
+use config::Config;
+use socks_beta::metrics::Metrics;
+use socks_beta::shutdown::{self, Shutdown};
+use socks_beta::stats::StatsHandle;
+use socks_beta::SocksServer;
+use tokio::runtime::Runtime;
+use tracing::{error, warn};
 
 
-#[repr(u8)]
-enum SOCKSReply {
-    Succeeded = 0x00,
-    GeneralSOCKSServerFailture = 0x01,
-    ConnectionNotAllowedByRuleset = 0x02,
-    NetworkUnreachable = 0x03,
-    HostUnreachable = 0x04,
-    ConnectionRefused = 0x05,
-    TTLExpired = 0x06,
-    CommandNotSupported = 0x07,
-    AddressTypeNotSupported = 0x08,
-}
-
-
-mod request_errors {
-    use std::error::Error;
-    use std::fmt::Display;
-
-    #[derive(Debug)]
-    pub struct CommandNotAllowedError();
-
-    impl Display for CommandNotAllowedError {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            write!(f, "Method is not allowed")
-        }
-    }
-
-    impl Error for CommandNotAllowedError {}
+/// Пауза перед перезапуском упавшего воркера
+const RESTART_DELAY: Duration = Duration::from_secs(1);
 
 
-    #[derive(Debug)]
-    pub struct AddressNotAllowed();
-    
-    impl Display for AddressNotAllowed {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            write!(f, "Address type is not allowed")
-        }
-    }
-
-    impl Error for AddressNotAllowed {}
-
-
-}
-
-
-fn reply(client_stream: &mut TcpStream, version: u8, reply: SOCKSReply, target_addr: &SocketAddr) -> Result<()> {
-    let mut reply = vec![version, reply as u8, 0x00];
-    if let IpAddr::V4(v4) = target_addr.ip() {
-        reply.push(0x01);
-        reply.extend_from_slice(&v4.octets());
+fn main() {
+    // Получаем настройки из файла (--config) и параметров программы;
+    // параметры перекрывают значения из файла
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => Config::from_file(args.get(i + 1).expect("--config requires a value")),
+        None => Ok(Config::default()),
     }
-    reply.extend_from_slice(&target_addr.port().to_be_bytes());
-    client_stream.write_all(&reply)?;
-    client_stream.flush()?;
-    Ok(())
-}
-
-fn process_method(client_stream: &mut TcpStream) -> Result<u8> /* version */ {
-    
-    let mut buf = [0; 2];
-    client_stream.read_exact(&mut buf)?;
-    let version = buf[0];
-    let num_methods = buf[1];
+        .and_then(|config| config.merge_args(args))
+        .unwrap_or_else(|e| panic!("{:#}", e));
 
-    let mut methods_buf = vec![0; num_methods as usize];
-    client_stream.read_exact(&mut methods_buf)?;
-
-    let chosen_method: u8 = 0x00; // Выбираем метод без аутентификации
-    client_stream.write_all(&[version, chosen_method])?;
-    client_stream.flush()?;
-    Ok(version)
-}
-
-fn process_request(client_stream: &mut TcpStream) -> Result<SocketAddr> {
-    use request_errors::*;
-
-    let mut cmd_buf = [0; 4];
-    client_stream.read_exact(&mut cmd_buf)?;
-    let cmd = cmd_buf[1];
-    let addr_type = cmd_buf[3];
-
-    // Обрабатываем только команду "establish a TCP/IP stream connection"
-    if cmd != 0x01 {
-        return Err(CommandNotAllowedError().into());
+    if config.dump_config {
+        print!("{}", config.dump().unwrap_or_else(|e| panic!("{:#}", e)));
+        return;
     }
 
-    // Читаем адрес назначения
-    let target_addr = match addr_type {
-        0x01 => {
-            // IPv4 адрес
-            let mut ip_buf = [0; 4];
-            client_stream.read_exact(&mut ip_buf)?;
-            let ip = ip_buf.iter()
-                .map(|b| b.to_string())
-                .collect::<Vec<String>>()
-                .join(".");
-            let mut port_buf = [0; 2];
-            client_stream.read_exact(&mut port_buf)?;
-            let port = u16::from_be_bytes(port_buf);
-            let addrs = (IpAddr::from(ip_buf), port)
-                .to_socket_addrs()
-                .unwrap()
-                .next()
-                .unwrap();
-            addrs
+    // Уходим в фон до запуска любых потоков; родитель завершится, когда
+    // серверы откроют порты
+    let daemon = config.daemon.then(|| daemon::daemonize(config.pid_file.as_deref()).unwrap_or_else(|e| panic!("{:#}", e)));
+
+    let log_level = config.log_level().unwrap_or_else(|e| panic!("{:#}", e));
+    tracing_subscriber::fmt()
+        .with_max_level(log_level)
+        .with_ansi(std::io::stdout().is_terminal())
+        .init();
+
+    // Ctrl-C и SIGTERM останавливают приём соединений, активные дообслуживаются;
+    // SIGHUP переоткрывает журнал доступа после ротации
+    let access_log = config.access_logger().unwrap_or_else(|e| panic!("{:#}", e));
+    let shutdown = Shutdown::new();
+    let hangup_log = access_log.clone();
+    shutdown::install_signal_handler(shutdown.clone(), move || {
+        if let Some(access_log) = &hangup_log {
+            access_log.reopen();
         }
-        0x03 => {
-            // Доменное имя
-            let mut len_buf = [0; 1];
-            client_stream.read_exact(&mut len_buf)?;
-            let len = len_buf[0] as usize;
-            let mut domain_buf = vec![0; len];
-            client_stream.read_exact(&mut domain_buf)?;
-            let domain = String::from_utf8(domain_buf)?;
-            let mut port_buf = [0; 2];
-            client_stream.read_exact(&mut port_buf)?;
-            let port = u16::from_be_bytes(port_buf);
-            format!("{}:{}", domain, port)
-                .to_socket_addrs()
-                .unwrap()
-                .next()
-                .unwrap()
+    })
+        .expect("failed to install signal handler");
+
+    // Каждый воркер - отдельный сервер со своим рантаймом и сокетом на общем порту
+    // (SO_REUSEPORT), на каждый порт запускается свой набор воркеров. Счётчики,
+    // список соединений и лимиты соединений общие, страницу метрик, сокет статистики
+    // и Unix сокет обслуживает первый воркер первого порта
+    let workers = config.workers().unwrap_or_else(|e| panic!("{:#}", e));
+    let listeners = config.listeners().unwrap_or_else(|e| panic!("{:#}", e));
+    let metrics = Arc::new(Metrics::default());
+    let stats = StatsHandle::default();
+    let conn_limit = config.connection_limit().unwrap_or_else(|e| panic!("{:#}", e));
+    // Сокеты от systemd занимают место слушателей с тем же портом; воркеры
+    // порта принимают соединения на копиях одного сокета
+    let mut activated = socks_beta::systemd::listen_fds().unwrap_or_else(|e| panic!("{:#}", e));
+    let port_of = |socket: &std::net::TcpListener| socket.local_addr().map_or(0, |addr| addr.port());
+    // Лишние сокеты закрываются, чтобы клиенты не ждали на них ответа
+    activated.retain(|socket| {
+        let port = port_of(socket);
+        let used = listeners.iter().any(|listener| listener.bind_addr().is_ok_and(|addr| addr.port() == port));
+        if !used {
+            warn!("socket-activated listener on port {} does not match any configured port, closing it", port);
         }
-        _ => return Err(AddressNotAllowed().into()),
-    };
-    Ok(target_addr)
-}
-
-
-
-
-fn serve(target_stream: &mut TcpStream, client_stream: &mut TcpStream) -> Result<()> {
-    let mut client_buffer = [0; 4096];
-    let mut target_buffer = [0; 4096];
-
-    client_stream.set_nonblocking(true)?;
-    target_stream.set_nonblocking(true)?;
-
-
-    loop {
-        let mut client_closed = false;
-        let mut target_closed = false;
-        
-        match client_stream.read(&mut client_buffer) {
-            Ok(0) => {
-                client_closed = true;
-            }
-            Ok(n) => {
-                target_stream.write_all(&client_buffer[..n])?;
-                target_stream.flush()?;
+        used
+    });
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let handles: Vec<_> = listeners
+        .iter()
+        .flat_map(|listener| (0..workers).map(move |worker| (listener, worker)))
+        .map(|(listener, worker)| {
+            let port = listener.bind_addr().map_or(0, |addr| addr.port());
+            let name = format!("worker-{}-{}", port, worker);
+            let socket = activated.iter().find(|&socket| port_of(socket) == port);
+            let mut worker_config = listener.clone();
+            if worker > 0 {
+                worker_config.metrics_addr = None;
+                worker_config.stats_socket = None;
+                worker_config.unix_socket = None;
             }
-            Err(e) => {
-                if e.kind() != io::ErrorKind::WouldBlock {
-                    client_closed = true;
+            let runtime = worker_config.runtime().unwrap_or_else(|e| panic!("{:#}", e));
+            let metrics = Arc::clone(&metrics);
+            let stats = stats.clone();
+            let conn_limit = conn_limit.clone();
+            let worker_shutdown = Arc::clone(&shutdown);
+            let access_log = access_log.clone();
+            let socket = socket.map(|socket| socket.try_clone().expect("failed to duplicate socket-activated listener"));
+            let build = move || {
+                let mut server = worker_config
+                    .server()
+                    .unwrap_or_else(|e| panic!("{:#}", e))
+                    .with_metrics(Arc::clone(&metrics))
+                    .with_stats(stats.clone())
+                    .with_connection_limit(conn_limit.clone())
+                    .with_shutdown(Arc::clone(&worker_shutdown));
+                if let Some(access_log) = &access_log {
+                    server = server.with_access_log(access_log.clone());
                 }
-            }
-        }
-        match target_stream.read(&mut target_buffer) {
-            Ok(0) => {
-                target_closed = true;
-            }
-            Ok(n) => {
-                client_stream.write_all(&target_buffer[..n])?;
-                client_stream.flush()?;
-            }
-            Err(e) => {
-                if e.kind() != io::ErrorKind::WouldBlock {
-                    target_closed = true;
+                if let Some(socket) = &socket {
+                    server = server.with_listener(socket.try_clone().expect("failed to duplicate socket-activated listener"));
                 }
-            }
-        }
-        
-        if client_closed || target_closed {
-            break;
+                server
+            };
+            // Первый сервер собирается здесь, чтобы ошибки настроек всплыли сразу
+            let server = build().with_ready_signal(ready_tx.clone());
+            let shutdown = Arc::clone(&shutdown);
+            thread::Builder::new()
+                .name(name)
+                .spawn(move || supervise(&runtime, server, build, &shutdown))
+                .expect("failed to spawn worker thread")
+        })
+        .collect();
+    drop(ready_tx);
+    // Если какой-то сервер не запустился, его ошибка всплывёт при join ниже,
+    // пока stderr ещё не отвязан
+    if let Some(daemon) = &daemon {
+        if ready_rx.iter().take(handles.len()).count() == handles.len() {
+            daemon.ready().unwrap_or_else(|e| panic!("{:#}", e));
         }
     }
-    Ok(())
+    for handle in handles {
+        handle.join().expect("worker panicked").expect("server failure");
+    }
 }
 
 
-fn serve_epoll(target_stream: &mut TcpStream, client_stream: &mut TcpStream) -> Result<()> {
-
-    let mut client_buffer = [0; 4096];
-    let mut target_buffer = [0; 4096];
-
-    client_stream.set_nonblocking(true)?;
-    target_stream.set_nonblocking(true)?;
-
-    let poller = Poller::new()?;
-    unsafe {
-        poller.add(client_stream as &TcpStream, Event::readable(1))?;
-        poller.add(target_stream as &TcpStream, Event::readable(2))?;
-    }
-
-    let mut events = Events::new();
+/// Запускает сервер воркера и перезапускает его с новым слушателем, если
+/// цикл приёма соединений запаниковал: без менеджера процессов паника
+/// иначе оставила бы порт без сервера. Ошибки запуска не перехватываются
+fn supervise(runtime: &Runtime, mut server: SocksServer, build: impl Fn() -> SocksServer, shutdown: &Shutdown) -> anyhow::Result<()> {
     loop {
-        let mut client_closed = false;
-        let mut target_closed = false;
-        events.clear();
-        poller.wait(&mut events, None)?;
-        
-        for event in events.iter() {
-            println!("new event! {:?}", event);
-            match event.key {
-                1 => match client_stream.read(&mut client_buffer) {
-                    Ok(0) => {
-                        client_closed = true;
-                    }
-                    Ok(n) => {
-                        target_stream.write_all(&client_buffer[..n])?;
-                        target_stream.flush()?;
-                        poller.modify(client_stream as &TcpStream, Event::readable(1))?;
-                        poller.modify(target_stream as &TcpStream, Event::readable(2))?;
-                    }
-                    Err(e) => {
-                        client_closed = true;
-                    }
-                }
-                2 => match target_stream.read(&mut target_buffer) {
-                    Ok(0) => {
-                        target_closed = true;
-                    }
-                    Ok(n) => {
-                        client_stream.write_all(&target_buffer[..n])?;
-                        client_stream.flush()?;
-                        poller.modify(target_stream as &TcpStream, Event::readable(2))?;
-                        poller.modify(client_stream as &TcpStream, Event::readable(1))?;
-                    }
-                    Err(e) => {
-                        target_closed = true;
-                    }
+        match panic::catch_unwind(AssertUnwindSafe(|| runtime.block_on(server.run()))) {
+            Ok(result) => return result,
+            Err(payload) => {
+                error!("server panicked: {}, restarting in {:?}", panic_message(&*payload), RESTART_DELAY);
+                thread::sleep(RESTART_DELAY);
+                if shutdown.is_requested() {
+                    return Ok(());
                 }
-                _ => {}
+                server = build();
             }
         }
-        if client_closed || target_closed {
-            break;
-        }
     }
-    Ok(())
 }
 
-
-fn handle_client(mut client_stream: TcpStream, cons: &mut HashMap<usize, Connection>, current_key: &mut usize, poller: &Poller) {
-    if let Ok(version) = process_method(&mut client_stream) {
-        println!("version: {}", version);
-        if let Ok(target_addr) = process_request(&mut client_stream) {
-            if let Ok(mut target_stream) = TcpStream::connect(&target_addr) {
-                println!("target stream: {:?}", target_stream);
-                if let Ok(_) = reply(&mut client_stream, version, SOCKSReply::Succeeded, &target_addr) {
-                    // let _ = serve_epoll(&mut target_stream, &mut client_stream);
-                    // println!("done to {:?}", target_stream);
-                    client_stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
-                    target_stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
-                    // client_stream.set_nonblocking(true).unwrap();
-                    // target_stream.set_nonblocking(true).unwrap();
-                    unsafe {
-                        poller.add(&client_stream, Event::readable(*current_key)).unwrap();
-                        poller.add(&target_stream, Event::readable(*current_key + 1)).unwrap();
-                    }
-                    cons.insert(*current_key, Connection { con: client_stream, other_key: *current_key + 1 });
-                    cons.insert(*current_key + 1, Connection { con: target_stream, other_key: *current_key });
-                    *current_key += 2;
-                    println!("inserted all");
-                    return;
-                }
-                
-                let _ = target_stream.shutdown(std::net::Shutdown::Both);
-
-            } else {
-                println!("connection error");
-                let _ = reply(&mut client_stream, version, SOCKSReply::GeneralSOCKSServerFailture, &target_addr);
-            }
-        } else {
-            println!("request error");
-        }
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
     } else {
-        println!("method error");
-    }
-
-    let _ = client_stream.shutdown(std::net::Shutdown::Both);
-}
-    
-
-struct Connection {
-    con: TcpStream,
-    other_key: usize,
-}
-
-impl Connection {
-    fn write_into(&mut self, buf: &[u8]) -> Result<()> {
-        self.con.write_all(buf)?;
-        self.con.flush()?;
-        Ok(())
+        "unknown panic"
     }
 }
-
-
-fn main() {
-    // Получаем порт из параметров программы
-    let port: u16 = std::env::args()
-        .nth(1)
-        .or_else(|| {
-            println!("Port is not passed. Using 9150...");
-            Some("9150".to_owned())
-        })
-        .expect("Valid or default argument")
-        .parse()
-        .expect("Invalid port number");
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
-
-    let poller = Poller::new().expect("poller creation");
-    unsafe {
-        poller.add(&listener, Event::readable(1)).expect("added listener to poller");
-    }
-    let mut events = Events::new();
-
-    let mut cons = HashMap::<usize, Connection>::new();
-
-    let mut current_key = 2usize;
-
-    loop {
-        let mut buffer = [0; 4096];
-
-        events.clear();
-        poller.wait(&mut events, None).unwrap();
-        for event in events.iter() {
-            match event.key {
-                1 => {
-                    for stream in listener.incoming() {
-                        if let Ok(client_stream) = stream {
-                            println!("new con! {:?}", client_stream);
-                            handle_client(client_stream, &mut cons, &mut current_key, &poller);
-                            break;
-                        }
-                    }
-                    poller.modify(&listener, Event::readable(1)).unwrap();
-                }
-                key => {
-                    if cons.contains_key(&key) {
-                        let (target_key, read_r) = {
-                            let con = cons.get_mut(&key).unwrap();
-                            poller.modify(&con.con, Event::readable(key)).unwrap();
-                            (con.other_key, con.con.read(&mut buffer))
-                        };
-                        match read_r {
-                            Ok(0) | Err(_) => {
-                                {
-                                    let con = cons.get_mut(&key).unwrap();
-                                    poller.delete(&con.con).unwrap();
-                                    cons.remove(&key);
-                                }
-                                {
-                                    let con = cons.get_mut(&target_key).unwrap();
-                                    poller.delete(&con.con).unwrap();
-                                    cons.remove(&target_key);
-                                }
-                            }
-                            Ok(n) => {
-                                if let Err(_) = cons.get_mut(&target_key).unwrap().write_into(&buffer[..n]) {
-                                    {
-                                        let con = cons.get_mut(&key).unwrap();
-                                        poller.delete(&con.con).unwrap();
-                                        cons.remove(&key);
-                                    }
-                                    {
-                                        let con = cons.get_mut(&target_key).unwrap();
-                                        poller.delete(&con.con).unwrap();
-                                        cons.remove(&target_key);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    }
-}
\ No newline at end of file
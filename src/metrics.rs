@@ -0,0 +1,145 @@
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+
+/// Сколько ждать запроса от HTTP клиента страницы метрик
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+
+/// Счётчики сервера. Обновляются из всех обработчиков, поэтому атомарные
+#[derive(Debug, Default)]
+pub struct Metrics {
+    active_connections: AtomicU64,
+    /// Байты, отправленные клиентам
+    bytes_sent: AtomicU64,
+    /// Байты, полученные от клиентов
+    bytes_received: AtomicU64,
+    connection_errors: AtomicU64,
+    /// UDP датаграммы с FRAG != 0, отброшенные без сборки
+    fragmented_datagrams: AtomicU64,
+    /// UDP датаграммы на домены, которые не удалось резолвить
+    unresolved_datagrams: AtomicU64,
+    /// Запросы к IP адресам, отклонённые из-за требования доменных имён
+    ip_literal_rejected: AtomicU64,
+    /// Собеседники UDP ассоциаций, забытые по простою или из-за лимита
+    udp_peers_evicted: AtomicU64,
+    /// UDP датаграммы больше допустимого размера, отброшенные целиком
+    oversized_datagrams: AtomicU64,
+}
+
+impl Metrics {
+    pub fn add_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn connection_error(&self) {
+        self.connection_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn fragmented_datagram(&self) {
+        self.fragmented_datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn unresolved_datagram(&self) {
+        self.unresolved_datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn ip_literal_rejected(&self) {
+        self.ip_literal_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn oversized_datagram(&self) {
+        self.oversized_datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn udp_peers_evicted(&self, count: usize) {
+        self.udp_peers_evicted.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Учитывает соединение как активное, пока жив возвращённый guard
+    pub fn track_connection(&self) -> ConnectionGuard<'_> {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { metrics: self }
+    }
+
+    /// Страница в текстовом формате Prometheus
+    pub fn render(&self) -> String {
+        let metrics = [
+            ("active_connections", "gauge", "Client connections being served", &self.active_connections),
+            ("total_bytes_sent", "counter", "Bytes relayed to clients over TCP", &self.bytes_sent),
+            ("total_bytes_received", "counter", "Bytes relayed from clients over TCP", &self.bytes_received),
+            ("connection_errors", "counter", "Failed handshakes, target connections and relays", &self.connection_errors),
+            ("udp_fragmented_datagrams", "counter", "UDP datagrams dropped because fragmentation is not supported", &self.fragmented_datagrams),
+            ("udp_unresolved_datagrams", "counter", "UDP datagrams dropped because the destination name did not resolve", &self.unresolved_datagrams),
+            ("ip_literal_rejected", "counter", "Requests rejected because they named an IP address instead of a host name", &self.ip_literal_rejected),
+            ("udp_oversized_datagrams", "counter", "UDP datagrams dropped because they exceed the maximum datagram size", &self.oversized_datagrams),
+            ("udp_peers_evicted", "counter", "UDP association peers forgotten after being idle or to stay under the peer limit", &self.udp_peers_evicted),
+        ];
+        let mut page = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(page, "# HELP {} {}", name, help);
+            let _ = writeln!(page, "# TYPE {} {}", name, kind);
+            let _ = writeln!(page, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        page
+    }
+}
+
+
+/// Уменьшает число активных соединений при завершении обработчика,
+/// в том числе при панике
+pub struct ConnectionGuard<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+
+/// Минимальный HTTP сервер: на `GET /metrics` отдаёт счётчики, на остальное - 404.
+/// Запросы обслуживаются по одному, в отдельной задаче
+pub(crate) async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                if let Err(e) = respond(stream, &metrics).await {
+                    debug!("metrics request error: {}", e);
+                }
+            }
+            Err(e) => warn!("metrics accept error: {}", e),
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    let mut request_line = String::new();
+    timeout(REQUEST_TIMEOUT, BufReader::new(&mut stream).read_line(&mut request_line)).await??;
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await
+}
@@ -0,0 +1,117 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use anyhow::{ensure, Context as _, Result};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use crate::stream::ClientStream;
+
+
+/// Ключ обфускации. Это не шифрование: XOR с повторяющимся ключом лишь
+/// прячет от DPI узнаваемые байты SOCKS (версию, методы), ничего не защищая
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObfsKey(Arc<[u8]>);
+
+impl ObfsKey {
+    pub fn new(key: &[u8]) -> ObfsKey {
+        assert!(!key.is_empty(), "Obfuscation key must not be empty");
+        ObfsKey(key.into())
+    }
+
+    /// XOR `buf` с ключом, начиная с байта потока `pos`
+    fn apply(&self, buf: &mut [u8], pos: u64) {
+        let len = self.0.len() as u64;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte ^= self.0[((pos + i as u64) % len) as usize];
+        }
+    }
+}
+
+impl FromStr for ObfsKey {
+    type Err = anyhow::Error;
+
+    /// Шестнадцатеричная строка чётной длины
+    fn from_str(s: &str) -> Result<ObfsKey> {
+        ensure!(!s.is_empty() && s.len().is_multiple_of(2) && s.is_ascii(), "Obfuscation key must be a non-empty hex string of even length");
+        let key = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .context("Obfuscation key must be a hex string")?;
+        Ok(ObfsKey::new(&key))
+    }
+}
+
+
+/// Поток, в котором каждый байт в обе стороны XOR с ключом. Позиция в ключе
+/// у каждого направления своя и идёт от начала соединения, так что обе
+/// стороны должны обернуть поток сразу после подключения
+pub struct XorStream<T> {
+    inner: T,
+    key: ObfsKey,
+    read_pos: u64,
+    write_pos: u64,
+    /// Зашифрованная копия данных для записи: исходный буфер неизменяем
+    scratch: Vec<u8>,
+}
+
+impl<T> XorStream<T> {
+    pub fn new(inner: T, key: ObfsKey) -> XorStream<T> {
+        XorStream { inner, key, read_pos: 0, write_pos: 0, scratch: Vec::new() }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for XorStream<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let received = &mut buf.filled_mut()[start..];
+        this.key.apply(received, this.read_pos);
+        this.read_pos += received.len() as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for XorStream<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Повторный вызов после Pending шифрует те же байты с той же позиции
+        this.scratch.clear();
+        this.scratch.extend_from_slice(buf);
+        this.key.apply(&mut this.scratch, this.write_pos);
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &this.scratch))?;
+        this.write_pos += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl ClientStream for XorStream<TcpStream> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        ClientStream::set_linger(&self.inner, linger)
+    }
+}
@@ -0,0 +1,288 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::{fmt, io};
+use crate::{unmap_ipv6, AddressType, Command, SocksError, SOCKS5_VERSION};
+
+
+/// Наибольшие размеры сообщений рукопожатия: длины полей занимают по байту,
+/// так что больше честный клиент прислать не может
+pub const MAX_GREETING_LEN: usize = 2 + 255;
+pub const MAX_REQUEST_LEN: usize = 4 + 1 + 255 + 2;
+pub const MAX_AUTH_LEN: usize = 2 + 255 + 1 + 255;
+
+
+/// Почему не удалось разобрать сообщение SOCKS5
+#[derive(Debug)]
+pub enum ParseError {
+    /// Сообщение пришло не целиком: нужно ещё хотя бы столько байт
+    NeedMore(usize),
+    /// Сообщение некорректно; внутри `SocksError`, по которой
+    /// выбирается код ответа
+    Malformed(anyhow::Error),
+}
+
+impl ParseError {
+    /// Ошибка для обработчика: нехватка байт в конце потока - обрыв соединения
+    pub fn into_error(self) -> anyhow::Error {
+        match self {
+            ParseError::NeedMore(_) => io::Error::from(io::ErrorKind::UnexpectedEof).into(),
+            ParseError::Malformed(e) => e,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::NeedMore(more) => write!(f, "Message is incomplete, {} more bytes needed", more),
+            ParseError::Malformed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+fn malformed(e: SocksError) -> ParseError {
+    ParseError::Malformed(e.into())
+}
+
+/// Ошибка, если в `buf` меньше `len` байт. Сообщение длиннее `max` - мусор,
+/// и дочитывать его нельзя
+fn need(buf: &[u8], len: usize, max: usize) -> Result<(), ParseError> {
+    if len > max {
+        return Err(malformed(SocksError::HandshakeTooLong(max)));
+    }
+    match len.checked_sub(buf.len()) {
+        Some(more) if more > 0 => Err(ParseError::NeedMore(more)),
+        _ => Ok(()),
+    }
+}
+
+
+/// Адрес назначения: IP или доменное имя, которое ещё предстоит резолвить
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address<'a> {
+    Ip(SocketAddr),
+    /// Доменное имя как есть, байтами из сообщения, и порт
+    Domain(&'a [u8], u16),
+}
+
+impl Address<'_> {
+    pub fn port(&self) -> u16 {
+        match self {
+            Address::Ip(addr) => addr.port(),
+            Address::Domain(_, port) => *port,
+        }
+    }
+}
+
+/// Разбирает ATYP, DST.ADDR и DST.PORT. Возвращает адрес и число занятых им байт
+pub fn parse_address(buf: &[u8]) -> Result<(Address<'_>, usize), ParseError> {
+    // Длиннее адрес быть не может, предел нужен только для `need`
+    const MAX: usize = 1 + 1 + 255 + 2;
+    need(buf, 1, MAX)?;
+    match AddressType::try_from(buf[0]).map_err(malformed)? {
+        AddressType::V4 => {
+            need(buf, 1 + 4 + 2, MAX)?;
+            let ip = Ipv4Addr::new(buf[1], buf[2], buf[3], buf[4]);
+            let port = u16::from_be_bytes([buf[5], buf[6]]);
+            Ok((Address::Ip(SocketAddr::from((ip, port))), 1 + 4 + 2))
+        }
+        AddressType::Domain => {
+            need(buf, 2, MAX)?;
+            let len = buf[1] as usize;
+            // Пустое имя - ошибка протокола, а не домен, который не удалось найти
+            if len == 0 {
+                return Err(malformed(SocksError::MalformedRequest));
+            }
+            need(buf, 2 + len + 2, MAX)?;
+            let port = u16::from_be_bytes([buf[2 + len], buf[3 + len]]);
+            Ok((Address::Domain(&buf[2..2 + len], port), 2 + len + 2))
+        }
+        AddressType::V6 => {
+            need(buf, 1 + 16 + 2, MAX)?;
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&buf[1..17]);
+            let port = u16::from_be_bytes([buf[17], buf[18]]);
+            Ok((Address::Ip(SocketAddr::new(unmap_ipv6(Ipv6Addr::from(ip)), port)), 1 + 16 + 2))
+        }
+    }
+}
+
+
+/// Приветствие клиента: VER, NMETHODS, METHODS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Greeting<'a> {
+    /// Предложенные методы аутентификации; пустой список некорректен, но
+    /// отказ на него отправляет обработчик
+    pub methods: &'a [u8],
+}
+
+pub fn parse_greeting(buf: &[u8]) -> Result<Greeting<'_>, ParseError> {
+    need(buf, 2, MAX_GREETING_LEN)?;
+    if buf[0] != SOCKS5_VERSION {
+        return Err(malformed(SocksError::UnsupportedVersion(buf[0])));
+    }
+    let len = 2 + buf[1] as usize;
+    need(buf, len, MAX_GREETING_LEN)?;
+    Ok(Greeting { methods: &buf[2..len] })
+}
+
+
+/// Запрос SOCKS5 до резолвинга: VER, CMD, RSV, ATYP, DST.ADDR, DST.PORT
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHeader<'a> {
+    pub command: Command,
+    pub target: Address<'a>,
+}
+
+/// Версия, RSV и команда проверяются, как только получены первые 4 байта,
+/// не дожидаясь адреса
+pub fn parse_request(buf: &[u8]) -> Result<RequestHeader<'_>, ParseError> {
+    need(buf, 4, MAX_REQUEST_LEN)?;
+    // VER должен совпадать с версией приветствия, RSV - быть нулевым
+    if buf[0] != SOCKS5_VERSION {
+        return Err(malformed(SocksError::UnsupportedVersion(buf[0])));
+    }
+    if buf[2] != 0x00 {
+        return Err(malformed(SocksError::MalformedRequest));
+    }
+    // Обрабатываем команды CONNECT ("establish a TCP/IP stream connection"), BIND и UDP ASSOCIATE
+    let command = Command::try_from(buf[1]).map_err(malformed)?;
+    let (target, len) = parse_address(&buf[3..])?;
+    need(buf, 3 + len, MAX_REQUEST_LEN)?;
+    // В BIND и UDP ASSOCIATE нулевой порт законен: клиент его ещё не знает
+    if command == Command::Connect && target.port() == 0 {
+        return Err(malformed(SocksError::ZeroPort));
+    }
+    Ok(RequestHeader { command, target })
+}
+
+
+/// Подсогласование логина/пароля (RFC 1929): VER(0x01), ULEN, UNAME, PLEN, PASSWD
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthRequest<'a> {
+    pub username: &'a [u8],
+    pub password: &'a [u8],
+}
+
+/// С версией, отличной от 0x01, возвращает `SocksError::UnsupportedVersion`: остальные
+/// поля неизвестно как читать
+pub fn parse_auth(buf: &[u8]) -> Result<AuthRequest<'_>, ParseError> {
+    need(buf, 1, MAX_AUTH_LEN)?;
+    if buf[0] != 0x01 {
+        return Err(malformed(SocksError::UnsupportedVersion(buf[0])));
+    }
+    need(buf, 2, MAX_AUTH_LEN)?;
+    let username_end = 2 + buf[1] as usize;
+    need(buf, username_end + 1, MAX_AUTH_LEN)?;
+    let password_end = username_end + 1 + buf[username_end] as usize;
+    need(buf, password_end, MAX_AUTH_LEN)?;
+    Ok(AuthRequest { username: &buf[2..username_end], password: &buf[username_end + 1..password_end] })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn need_more<T: fmt::Debug>(result: Result<T, ParseError>) -> usize {
+        match result {
+            Err(ParseError::NeedMore(more)) => more,
+            other => panic!("expected NeedMore, got {:?}", other),
+        }
+    }
+
+    fn malformed_error<T: fmt::Debug>(result: Result<T, ParseError>) -> SocksError {
+        match result {
+            Err(ParseError::Malformed(e)) => e.downcast().expect("SocksError"),
+            other => panic!("expected Malformed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn greeting() {
+        assert_eq!(parse_greeting(&[0x05, 0x02, 0x00, 0x02]).unwrap(), Greeting { methods: &[0x00, 0x02] });
+        assert_eq!(parse_greeting(&[0x05, 0x00]).unwrap(), Greeting { methods: &[] });
+    }
+
+    #[test]
+    fn greeting_need_more() {
+        assert_eq!(need_more(parse_greeting(&[])), 2);
+        assert_eq!(need_more(parse_greeting(&[0x05])), 1);
+        assert_eq!(need_more(parse_greeting(&[0x05, 0x03, 0x00])), 2);
+    }
+
+    #[test]
+    fn greeting_malformed() {
+        assert!(matches!(malformed_error(parse_greeting(&[0x04, 0x01, 0x00])), SocksError::UnsupportedVersion(0x04)));
+    }
+
+    #[test]
+    fn request_ipv4() {
+        let header = parse_request(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50]).unwrap();
+        assert_eq!(header.command, Command::Connect);
+        assert_eq!(header.target, Address::Ip("127.0.0.1:80".parse().unwrap()));
+    }
+
+    #[test]
+    fn request_ipv6_mapped() {
+        let mut buf = vec![0x05, 0x01, 0x00, 0x04];
+        buf.extend_from_slice(&"::ffff:10.0.0.1".parse::<Ipv6Addr>().unwrap().octets());
+        buf.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(parse_request(&buf).unwrap().target, Address::Ip("10.0.0.1:443".parse().unwrap()));
+    }
+
+    #[test]
+    fn request_domain() {
+        let header = parse_request(b"\x05\x03\x00\x03\x0bexample.com\x00\x00").unwrap();
+        assert_eq!(header.command, Command::UdpAssociate);
+        assert_eq!(header.target, Address::Domain(b"example.com", 0));
+    }
+
+    #[test]
+    fn request_need_more() {
+        assert_eq!(need_more(parse_request(&[0x05, 0x01])), 2);
+        assert_eq!(need_more(parse_request(&[0x05, 0x01, 0x00, 0x01, 127, 0])), 4);
+        assert_eq!(need_more(parse_request(b"\x05\x01\x00\x03")), 1);
+        assert_eq!(need_more(parse_request(b"\x05\x01\x00\x03\x0bexample")), 6);
+    }
+
+    #[test]
+    fn request_malformed() {
+        assert!(matches!(malformed_error(parse_request(&[0x04, 0x01, 0x00, 0x01])), SocksError::UnsupportedVersion(0x04)));
+        assert!(matches!(malformed_error(parse_request(&[0x05, 0x01, 0x01, 0x01])), SocksError::MalformedRequest));
+        assert!(matches!(malformed_error(parse_request(&[0x05, 0x09, 0x00, 0x01])), SocksError::UnsupportedCommand));
+        assert!(matches!(malformed_error(parse_request(&[0x05, 0x01, 0x00, 0x03, 0x00, 0x00, 0x50])), SocksError::MalformedRequest));
+        assert!(matches!(malformed_error(parse_request(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 0])), SocksError::ZeroPort));
+    }
+
+    #[test]
+    fn request_unknown_address_type() {
+        let e = malformed_error(parse_request(&[0x05, 0x01, 0x00, 0x02, 127, 0, 0, 1, 0x00, 0x50]));
+        assert!(matches!(e, SocksError::UnsupportedAddressType));
+        assert_eq!(e.reply_code() as u8, 0x08);
+    }
+
+    #[test]
+    fn request_zero_port_allowed_for_bind() {
+        assert_eq!(parse_request(&[0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).unwrap().command, Command::Bind);
+    }
+
+    #[test]
+    fn auth() {
+        let auth = parse_auth(b"\x01\x04user\x06secret").unwrap();
+        assert_eq!(auth, AuthRequest { username: b"user", password: b"secret" });
+        assert_eq!(parse_auth(b"\x01\x00\x00").unwrap(), AuthRequest { username: b"", password: b"" });
+    }
+
+    #[test]
+    fn auth_need_more() {
+        assert_eq!(need_more(parse_auth(&[])), 1);
+        assert_eq!(need_more(parse_auth(b"\x01")), 1);
+        assert_eq!(need_more(parse_auth(b"\x01\x04us")), 3);
+        assert_eq!(need_more(parse_auth(b"\x01\x04user\x06sec")), 3);
+    }
+
+    #[test]
+    fn auth_malformed() {
+        assert!(matches!(malformed_error(parse_auth(b"\x05\x04user\x06secret")), SocksError::UnsupportedVersion(0x05)));
+    }
+}
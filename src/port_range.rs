@@ -0,0 +1,130 @@
+use std::future::Future;
+use std::io;
+use std::str::FromStr;
+use std::fmt;
+use anyhow::{bail, ensure, Context, Result};
+
+
+/// Диапазон локальных портов для сокетов, которые прокси открывает для
+/// клиента, чтобы под них можно было написать правила файрвола или NAT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PortRange {
+    pub fn new(first: u16, last: u16) -> PortRange {
+        assert!(first > 0 && first <= last, "Invalid port range");
+        PortRange { first, last }
+    }
+
+    /// Все порты по кругу, начиная со случайного: параллельные сессии не
+    /// перебирают одни и те же занятые порты с начала диапазона
+    fn ports(&self) -> impl Iterator<Item = u16> {
+        let len = (self.last - self.first) as u32 + 1;
+        let start = rand::random::<u32>() % len;
+        let first = self.first;
+        (0..len).map(move |i| first + ((start + i) % len) as u16)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    /// `first-last` или один порт
+    fn from_str(s: &str) -> Result<PortRange> {
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let first: u16 = first.trim().parse().with_context(|| format!("Invalid port range {:?}", s))?;
+        let last: u16 = last.trim().parse().with_context(|| format!("Invalid port range {:?}", s))?;
+        ensure!(first > 0 && first <= last, "Invalid port range {:?} (expected first-last, first not above last)", s);
+        Ok(PortRange { first, last })
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+
+/// Привязывает сокет к свободному порту из `range`; без диапазона порт
+/// выбирает система. Занятые порты пропускаются, другие ошибки возвращаются сразу
+pub(crate) async fn bind_in<S, F, Fut>(range: Option<PortRange>, mut bind: F) -> Result<S>
+where
+    F: FnMut(u16) -> Fut,
+    Fut: Future<Output = io::Result<S>>,
+{
+    let Some(range) = range else {
+        return Ok(bind(0).await?);
+    };
+    for port in range.ports() {
+        match bind(port).await {
+            Ok(socket) => return Ok(socket),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    bail!("No free port in range {}", range)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!("40000-40010".parse::<PortRange>().unwrap(), PortRange::new(40000, 40010));
+        assert_eq!(" 5000 - 5001 ".parse::<PortRange>().unwrap(), PortRange::new(5000, 5001));
+        assert_eq!("1080".parse::<PortRange>().unwrap(), PortRange::new(1080, 1080));
+        for invalid in ["", "0-10", "10-5", "1-65536", "a-b", "1-2-3"] {
+            assert!(invalid.parse::<PortRange>().is_err(), "{:?}", invalid);
+        }
+        assert_eq!(PortRange::new(1, 65535).to_string(), "1-65535");
+    }
+
+    #[test]
+    fn ports_cover_range_once() {
+        let mut ports: Vec<u16> = PortRange::new(65530, 65535).ports().collect();
+        ports.sort();
+        assert_eq!(ports, [65530, 65531, 65532, 65533, 65534, 65535]);
+    }
+
+    #[tokio::test]
+    async fn skips_ports_in_use() {
+        let port = bind_in(Some(PortRange::new(5000, 5009)), |port| async move {
+            if port == 5007 { Ok(port) } else { Err(io::Error::from(io::ErrorKind::AddrInUse)) }
+        }).await.unwrap();
+        assert_eq!(port, 5007);
+    }
+
+    #[tokio::test]
+    async fn range_exhausted() {
+        let mut tried = Vec::new();
+        let e = bind_in(Some(PortRange::new(5000, 5002)), |port| {
+            tried.push(port);
+            async { Err::<u16, _>(io::Error::from(io::ErrorKind::AddrInUse)) }
+        }).await.unwrap_err();
+        assert_eq!(e.to_string(), "No free port in range 5000-5002");
+        tried.sort();
+        assert_eq!(tried, [5000, 5001, 5002]);
+    }
+
+    #[tokio::test]
+    async fn other_errors_stop_search() {
+        let mut attempts = 0;
+        let e = bind_in(Some(PortRange::new(5000, 5002)), |_| {
+            attempts += 1;
+            async { Err::<u16, _>(io::Error::from(io::ErrorKind::PermissionDenied)) }
+        }).await.unwrap_err();
+        assert_eq!(e.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn without_range_system_picks_port() {
+        assert_eq!(bind_in(None, |port| async move { Ok(port) }).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,140 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use anyhow::{bail, ensure, Context, Result};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use crate::canonical_ip;
+
+
+/// Сигнатура, с которой начинается заголовок версии 2
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Наибольшая длина строки версии 1 вместе с CRLF
+const V1_MAX_LEN: usize = 107;
+
+
+/// Заголовок PROXY protocol v1: адрес клиента и адрес прокси, к которому он
+/// подключился. Клиенты IPv4 на IPv6 сокете передаются как TCP4; адреса
+/// разных семейств в заголовок не записать, тогда отправляется `UNKNOWN`
+pub(crate) fn header_v1(client: SocketAddr, server: SocketAddr) -> String {
+    match (canonical_ip(client.ip()), canonical_ip(server.ip())) {
+        (client_ip @ IpAddr::V4(_), server_ip @ IpAddr::V4(_)) => {
+            format!("PROXY TCP4 {} {} {} {}\r\n", client_ip, server_ip, client.port(), server.port())
+        }
+        (client_ip @ IpAddr::V6(_), server_ip @ IpAddr::V6(_)) => {
+            format!("PROXY TCP6 {} {} {} {}\r\n", client_ip, server_ip, client.port(), server.port())
+        }
+        _ => "PROXY UNKNOWN\r\n".to_owned(),
+    }
+}
+
+/// Двоичный заголовок PROXY protocol v2 (команда PROXY, TCP): адрес клиента
+/// и адрес назначения. Для адресов разных семейств - AF_UNSPEC без адресов,
+/// получатель тогда использует адреса соединения
+pub(crate) fn header_v2(client: SocketAddr, dest: SocketAddr) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    // Версия 2, команда PROXY
+    header.push(0x21);
+    let mut addrs = Vec::new();
+    let family = match (canonical_ip(client.ip()), canonical_ip(dest.ip())) {
+        (IpAddr::V4(client_ip), IpAddr::V4(dest_ip)) => {
+            addrs.extend_from_slice(&client_ip.octets());
+            addrs.extend_from_slice(&dest_ip.octets());
+            0x11
+        }
+        (IpAddr::V6(client_ip), IpAddr::V6(dest_ip)) => {
+            addrs.extend_from_slice(&client_ip.octets());
+            addrs.extend_from_slice(&dest_ip.octets());
+            0x21
+        }
+        _ => 0x00,
+    };
+    if family != 0x00 {
+        addrs.extend_from_slice(&client.port().to_be_bytes());
+        addrs.extend_from_slice(&dest.port().to_be_bytes());
+    }
+    header.push(family);
+    header.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+    header.extend_from_slice(&addrs);
+    header
+}
+
+
+/// Читает заголовок PROXY protocol v1 или v2, который балансировщик
+/// отправляет перед данными клиента, и ничего сверх него. Возвращает адрес
+/// клиента и адрес, к которому тот подключался; `None` для проверок самого
+/// балансировщика (v1 `UNKNOWN`, v2 `LOCAL`) - тогда верен адрес сокета
+pub(crate) async fn read_header(stream: &mut TcpStream) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    // Короче 12 байт не бывает ни одна версия: `PROXY UNKNOWN\r\n` - 15
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        let mut fixed = [0; 4];
+        stream.read_exact(&mut fixed).await?;
+        let mut body = vec![0; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+        stream.read_exact(&mut body).await?;
+        return parse_v2(fixed[0], fixed[1], &body);
+    }
+    ensure!(start.starts_with(b"PROXY "), "Connection does not start with a PROXY protocol header");
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        ensure!(line.len() < V1_MAX_LEN, "PROXY protocol header is longer than {} bytes", V1_MAX_LEN);
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).context("PROXY protocol header is not ASCII")?;
+    parse_v1(line)
+}
+
+/// `PROXY TCP4|TCP6 <src_ip> <dst_ip> <src_port> <dst_port>` или `PROXY UNKNOWN ...`
+fn parse_v1(line: &str) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    let mut fields = line.split(' ').skip(1);
+    let family = fields.next().unwrap_or_default();
+    if family == "UNKNOWN" {
+        return Ok(None);
+    }
+    let fields: Vec<&str> = fields.collect();
+    let [source_ip, dest_ip, source_port, dest_port] = fields[..] else {
+        bail!("Invalid PROXY protocol header {:?}", line);
+    };
+    let (source_ip, dest_ip): (IpAddr, IpAddr) = match family {
+        "TCP4" => (source_ip.parse::<Ipv4Addr>()?.into(), dest_ip.parse::<Ipv4Addr>()?.into()),
+        "TCP6" => (source_ip.parse::<Ipv6Addr>()?.into(), dest_ip.parse::<Ipv6Addr>()?.into()),
+        _ => bail!("Unknown PROXY protocol family {:?}", family),
+    };
+    let source = SocketAddr::new(source_ip, source_port.parse().context("Invalid PROXY protocol source port")?);
+    let dest = SocketAddr::new(dest_ip, dest_port.parse().context("Invalid PROXY protocol destination port")?);
+    Ok(Some((source, dest)))
+}
+
+/// Двоичный заголовок: версия и команда, семейство, затем адреса и TLV,
+/// которые пропускаются
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    ensure!(version_command >> 4 == 2, "Unsupported PROXY protocol version {}", version_command >> 4);
+    match version_command & 0x0F {
+        0x0 => return Ok(None),
+        0x1 => {}
+        command => bail!("Unknown PROXY protocol command {:#x}", command),
+    }
+    match family >> 4 {
+        // AF_INET: 4 + 4 + 2 + 2
+        0x1 => {
+            ensure!(body.len() >= 12, "PROXY protocol header is truncated");
+            let source_ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let dest_ip = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            let source = SocketAddr::from((source_ip, u16::from_be_bytes([body[8], body[9]])));
+            let dest = SocketAddr::from((dest_ip, u16::from_be_bytes([body[10], body[11]])));
+            Ok(Some((source, dest)))
+        }
+        // AF_INET6: 16 + 16 + 2 + 2
+        0x2 => {
+            ensure!(body.len() >= 36, "PROXY protocol header is truncated");
+            let mut source_ip = [0; 16];
+            source_ip.copy_from_slice(&body[..16]);
+            let mut dest_ip = [0; 16];
+            dest_ip.copy_from_slice(&body[16..32]);
+            let source = SocketAddr::from((Ipv6Addr::from(source_ip), u16::from_be_bytes([body[32], body[33]])));
+            let dest = SocketAddr::from((Ipv6Addr::from(dest_ip), u16::from_be_bytes([body[34], body[35]])));
+            Ok(Some((source, dest)))
+        }
+        // AF_UNSPEC и AF_UNIX: адреса клиента в понятном виде нет
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,68 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::{sleep, Sleep};
+
+
+/// Корзина токенов: пополняется на `rate` байт в секунду и вмещает не больше
+/// секунды трафика. Баланс может уйти в минус: большая порция передаётся
+/// целиком, зато следующая ждёт, пока долг не будет погашен
+pub struct TokenBucket {
+    /// Байт в секунду
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_second: u64) -> TokenBucket {
+        assert!(bytes_per_second > 0, "Rate limit must be positive");
+        let rate = bytes_per_second as f64;
+        TokenBucket { rate, tokens: rate, updated: Instant::now() }
+    }
+
+    /// Списывает `n` байт и возвращает, сколько нужно подождать перед следующей передачей
+    pub fn consume(&mut self, n: usize) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate) - n as f64;
+        self.updated = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+
+/// Ограничитель одного направления для poll-функций потоков: после передачи
+/// выдерживает паузу, которую потребовала корзина, не блокируя поток рантайма
+pub(crate) struct Throttle {
+    bucket: TokenBucket,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    pub(crate) fn new(bytes_per_second: u64) -> Throttle {
+        Throttle { bucket: TokenBucket::new(bytes_per_second), delay: None }
+    }
+
+    /// Готов ли следующий вызов передать данные
+    pub(crate) fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = &mut self.delay {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        Poll::Ready(())
+    }
+
+    /// Учитывает переданные `n` байт
+    pub(crate) fn record(&mut self, n: usize) {
+        let wait = self.bucket.consume(n);
+        if !wait.is_zero() {
+            self.delay = Some(Box::pin(sleep(wait)));
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::Arc;
+use anyhow::Result;
+use tokio::sync::watch;
+use tracing::info;
+
+
+/// Запрос на остановку сервера. Цикл приёма соединений ждёт его в `requested`,
+/// поэтому `request` будит цикл сразу, из любого потока
+pub struct Shutdown {
+    sender: watch::Sender<bool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Arc<Shutdown> {
+        Arc::new(Shutdown { sender: watch::Sender::new(false) })
+    }
+
+    /// Прекращает приём новых соединений; активные обслуживаются до конца
+    pub fn request(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Завершается, когда остановка запрошена (сразу, если уже запрошена)
+    pub async fn requested(&self) {
+        let mut receiver = self.sender.subscribe();
+        // Ошибка невозможна: отправитель живёт, пока жив `self`
+        let _ = receiver.wait_for(|requested| *requested).await;
+    }
+}
+
+
+/// Останавливает сервер по Ctrl-C, а на POSIX также по SIGTERM.
+/// Повторный сигнал завершает процесс немедленно. SIGHUP вызывает `on_hangup`,
+/// например чтобы переоткрыть журналы после ротации
+pub fn install_signal_handler(shutdown: Arc<Shutdown>, on_hangup: impl Fn() + Send + 'static) -> Result<()> {
+    let interrupted = Arc::clone(&shutdown);
+    ctrlc::set_handler(move || request_from_signal(&interrupted))?;
+
+    #[cfg(unix)]
+    {
+        use signal_hook::consts::{SIGHUP, SIGTERM};
+        let mut signals = signal_hook::iterator::Signals::new([SIGTERM, SIGHUP])?;
+        std::thread::Builder::new()
+            .name("signals".into())
+            .spawn(move || {
+                for signal in signals.forever() {
+                    if signal == SIGHUP {
+                        info!("SIGHUP received, reopening logs");
+                        on_hangup();
+                    } else {
+                        request_from_signal(&shutdown);
+                    }
+                }
+            })?;
+    }
+    #[cfg(not(unix))]
+    drop(on_hangup);
+    Ok(())
+}
+
+fn request_from_signal(shutdown: &Shutdown) {
+    if shutdown.is_requested() {
+        std::process::exit(130);
+    }
+    info!("Shutdown requested, no longer accepting connections");
+    shutdown.request();
+}
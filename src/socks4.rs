@@ -0,0 +1,233 @@
+use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use anyhow::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
+use crate::dns::{self, DnsResolver};
+use crate::acl::DomainMatcher;
+use crate::parse::Address;
+use crate::{check_blocked, is_ip_literal, Command, Request, SocksError};
+use crate::stream::ClientStream;
+
+
+/// Коды ответа SOCKS4 (поле CD)
+#[repr(u8)]
+#[allow(dead_code)]
+pub enum Socks4Reply {
+    Granted = 90,
+    Rejected = 91,
+    IdentUnreachable = 92,
+    IdentMismatch = 93,
+}
+
+
+/// Максимальная длина USERID и доменного имени SOCKS4a
+const MAX_FIELD_LEN: usize = 255;
+
+
+/// Отвечает клиенту 8-байтным пакетом: VN=0, CD, DSTPORT, DSTIP.
+/// Для адресов, не представимых в SOCKS4 (IPv6), передаются нули.
+pub async fn reply(client_stream: &mut dyn ClientStream, reply: Socks4Reply, target_addr: &SocketAddr) -> Result<()> {
+    let ip = match target_addr.ip() {
+        IpAddr::V4(v4) => v4,
+        IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+    };
+    let mut reply = vec![0x00, reply as u8];
+    reply.extend_from_slice(&target_addr.port().to_be_bytes());
+    reply.extend_from_slice(&ip.octets());
+    client_stream.write_all(&reply).await?;
+    client_stream.flush().await?;
+    Ok(())
+}
+
+
+/// Читает строку, завершённую нулевым байтом (USERID или домен SOCKS4a)
+async fn read_null_terminated(client_stream: &mut dyn ClientStream) -> Result<Vec<u8>> {
+    let mut field = Vec::new();
+    let mut byte = [0; 1];
+    loop {
+        client_stream.read_exact(&mut byte).await?;
+        if byte[0] == 0x00 {
+            return Ok(field);
+        }
+        if field.len() == MAX_FIELD_LEN {
+            return Err(SocksError::FieldTooLong.into());
+        }
+        field.push(byte[0]);
+    }
+}
+
+
+/// Разбирает запрос SOCKS4/SOCKS4a, включая байт версии:
+/// VN, CD, DSTPORT(2), DSTIP(4), USERID\0 [, DOMAIN\0]. С `require_hostname`
+/// запрос без домена SOCKS4a или с IP вместо домена отклоняется с
+/// `SocksError::IpLiteral`, запрос
+/// к имени из `blocked` - с `SocksError::DomainBlocked`. С `upstream_resolves`
+/// домен SOCKS4a не резолвится, его получает вышестоящий прокси
+pub async fn process_socks4_request(client_stream: &mut dyn ClientStream, dns: Option<&DnsResolver>, require_hostname: bool, blocked: &DomainMatcher, upstream_resolves: bool) -> Result<Request> {
+    let mut header = [0; 8];
+    client_stream.read_exact(&mut header).await?;
+    let cmd = header[1];
+    let port = u16::from_be_bytes([header[2], header[3]]);
+    let ip = Ipv4Addr::new(header[4], header[5], header[6], header[7]);
+
+    let user_id = read_null_terminated(client_stream).await?;
+    debug!("socks4 user id: {}", String::from_utf8_lossy(&user_id));
+
+    // Обрабатываем только CONNECT
+    if cmd != 0x01 {
+        return Err(SocksError::UnsupportedCommand.into());
+    }
+    if port == 0 {
+        return Err(SocksError::ZeroPort.into());
+    }
+
+    // SOCKS4a: адрес вида 0.0.0.x (x != 0) означает, что после USERID идёт домен
+    let octets = ip.octets();
+    if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        let domain = read_null_terminated(client_stream).await?;
+        let target = Address::Domain(&domain, port);
+        if require_hostname && is_ip_literal(&target) {
+            return Err(SocksError::IpLiteral.into());
+        }
+        check_blocked(blocked, &target)?;
+        if upstream_resolves && !is_ip_literal(&target) {
+            return Ok(Request::for_upstream(&domain, port));
+        }
+        let mut addrs = dns::resolve_all(&domain, port, dns).await?;
+        let target_addr = addrs.remove(0);
+        return Ok(Request { command: Command::Connect, target_addr, fallback_addrs: addrs, upstream_name: None });
+    }
+
+    if require_hostname {
+        return Err(SocksError::IpLiteral.into());
+    }
+    let target_addr = SocketAddr::from((ip, port));
+    check_blocked(blocked, &Address::Ip(target_addr))?;
+    Ok(Request { command: Command::Connect, target_addr, fallback_addrs: Vec::new(), upstream_name: None })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Соединённые сокеты: клиентский и серверный
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    /// Разбирает `request`, присланный клиентом, который после него закрыл запись
+    async fn process(request: &[u8], upstream_resolves: bool) -> Result<Request> {
+        let (mut client, mut server) = pair().await;
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+        process_socks4_request(&mut server, None, false, &DomainMatcher::new(), upstream_resolves).await
+    }
+
+    fn error(result: Result<Request>) -> SocksError {
+        match result {
+            Ok(_) => panic!("request unexpectedly accepted"),
+            Err(e) => e.downcast().expect("SocksError"),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_request() {
+        let request = process(b"\x04\x01\x00\x50\xc0\x00\x02\x01user\x00", false).await.unwrap();
+        assert_eq!(request.command, Command::Connect);
+        assert_eq!(request.target_addr, SocketAddr::from(([192, 0, 2, 1], 80)));
+        assert!(request.fallback_addrs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn empty_userid() {
+        let request = process(b"\x04\x01\x01\xbb\xc0\x00\x02\x01\x00", false).await.unwrap();
+        assert_eq!(request.target_addr, SocketAddr::from(([192, 0, 2, 1], 443)));
+    }
+
+    #[tokio::test]
+    async fn userid_without_terminator() {
+        let mut request = b"\x04\x01\x00\x50\xc0\x00\x02\x01".to_vec();
+        request.extend_from_slice(&[b'u'; MAX_FIELD_LEN + 10]);
+        assert!(matches!(error(process(&request, false).await), SocksError::FieldTooLong));
+        // Ровно MAX_FIELD_LEN байт - ещё допустимо
+        let mut request = b"\x04\x01\x00\x50\xc0\x00\x02\x01".to_vec();
+        request.extend_from_slice(&[b'u'; MAX_FIELD_LEN]);
+        request.push(0x00);
+        assert!(process(&request, false).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn truncated_request() {
+        assert!(process(b"\x04\x01\x00\x50\xc0\x00", false).await.is_err());
+        assert!(process(b"\x04\x01\x00\x50\xc0\x00\x02\x01user", false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unsupported_request() {
+        assert!(matches!(error(process(b"\x04\x02\x00\x50\xc0\x00\x02\x01\x00", false).await), SocksError::UnsupportedCommand));
+        assert!(matches!(error(process(b"\x04\x01\x00\x00\xc0\x00\x02\x01\x00", false).await), SocksError::ZeroPort));
+    }
+
+    #[tokio::test]
+    async fn rejected_reply_layout() {
+        let (mut client, mut server) = pair().await;
+        reply(&mut server, Socks4Reply::Rejected, &SocketAddr::from(([192, 0, 2, 1], 80))).await.unwrap();
+        reply(&mut server, Socks4Reply::Rejected, &"[::1]:443".parse().unwrap()).await.unwrap();
+        drop(server);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, [0x00, 0x5B, 0x00, 0x50, 192, 0, 2, 1, 0x00, 0x5B, 0x01, 0xbb, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn socks4a_domain() {
+        let request = process(b"\x04\x01\x00\x50\x00\x00\x00\x01user\x00example.test\x00", true).await.unwrap();
+        assert_eq!(request.upstream_name.as_deref(), Some(&b"example.test"[..]));
+        assert_eq!(request.target_addr.port(), 80);
+
+        let request = process(b"\x04\x01\x00\x50\x00\x00\x00\xff\x00localhost\x00", false).await.unwrap();
+        assert!(request.addrs().all(|addr| addr.ip().is_loopback() && addr.port() == 80));
+        assert_eq!(request.upstream_name, None);
+    }
+
+    #[tokio::test]
+    async fn socks4a_unresolvable_domain() {
+        // .invalid не резолвится никогда (RFC 6761); обработчик отвечает на это CD=91
+        let e = error(process(b"\x04\x01\x00\x50\x00\x00\x00\x01\x00example.invalid\x00", false).await);
+        assert!(matches!(e, SocksError::ResolutionFailed));
+    }
+
+    #[tokio::test]
+    async fn socks4a_domain_without_terminator() {
+        let mut request = b"\x04\x01\x00\x50\x00\x00\x00\x01\x00".to_vec();
+        request.extend_from_slice(&[b'a'; MAX_FIELD_LEN + 1]);
+        assert!(matches!(error(process(&request, false).await), SocksError::FieldTooLong));
+    }
+
+    #[tokio::test]
+    async fn zero_address_is_not_socks4a() {
+        // 0.0.0.0 - обычный адрес, домен после USERID не читается
+        let request = process(b"\x04\x01\x00\x50\x00\x00\x00\x00\x00", false).await.unwrap();
+        assert_eq!(request.target_addr, SocketAddr::from(([0, 0, 0, 0], 80)));
+    }
+
+    #[tokio::test]
+    async fn socks4a_checks() {
+        let (mut client, mut server) = pair().await;
+        client.write_all(b"\x04\x01\x00\x50\x00\x00\x00\x01\x00127.0.0.1\x00").await.unwrap();
+        let result = process_socks4_request(&mut server, None, true, &DomainMatcher::new(), false).await;
+        assert!(matches!(error(result), SocksError::IpLiteral));
+
+        let mut blocked = DomainMatcher::new();
+        blocked.add("*.internal").unwrap();
+        let (mut client, mut server) = pair().await;
+        client.write_all(b"\x04\x01\x00\x50\x00\x00\x00\x01\x00db.internal\x00").await.unwrap();
+        let result = process_socks4_request(&mut server, None, false, &blocked, true).await;
+        assert!(matches!(error(result), SocksError::DomainBlocked(_)));
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+/// Обслуживаемое соединение в снимке статистики
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub client: SocketAddr,
+    /// Адрес цели; нет, пока соединение с ней не установлено
+    pub target: Option<SocketAddr>,
+    /// Байт передано в обе стороны, обновляется во время ретрансляции
+    pub bytes: Arc<AtomicU64>,
+    pub started: SystemTime,
+}
+
+
+/// Список активных соединений. Клонируется в каждый воркер, список общий
+#[derive(Debug, Clone, Default)]
+pub struct StatsHandle {
+    connections: Arc<Mutex<Vec<ConnectionInfo>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl StatsHandle {
+    /// Добавляет соединение в список; оно удаляется, когда возвращённая
+    /// запись будет удалена, в том числе при панике обработчика
+    pub fn register(&self, client: SocketAddr, local: SocketAddr) -> ConnectionStats {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes = Arc::new(AtomicU64::new(0));
+        let info = ConnectionInfo { id, client, target: None, bytes: Arc::clone(&bytes), started: SystemTime::now() };
+        self.lock().push(info);
+        ConnectionStats { handle: self.clone(), id, peer: client, local, bytes, succeeded: AtomicBool::new(false) }
+    }
+
+    pub fn snapshot(&self) -> Vec<ConnectionInfo> {
+        self.lock().clone()
+    }
+
+    /// Снимок в JSON. Формат стабилен, поля только добавляются:
+    /// `{"connections":[{"id":1,"client":"ip:port","target":"ip:port"|null,"bytes":0,"started":unix_seconds}]}`
+    pub fn render_json(&self) -> String {
+        let mut json = String::from("{\"connections\":[");
+        for (i, info) in self.snapshot().iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            // Адреса и числа не содержат символов, которые нужно экранировать
+            let target = info.target.map_or("null".to_owned(), |target| format!("\"{}\"", target));
+            let started = info.started.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+            let _ = write!(
+                json,
+                "{{\"id\":{},\"client\":\"{}\",\"target\":{},\"bytes\":{},\"started\":{}}}",
+                info.id,
+                info.client,
+                target,
+                info.bytes.load(Ordering::Relaxed),
+                started
+            );
+        }
+        json.push_str("]}\n");
+        json
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<ConnectionInfo>> {
+        // Список остаётся согласованным, даже если кто-то запаниковал с блокировкой
+        self.connections.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+
+/// Запись обработчика о своём соединении
+#[derive(Debug)]
+pub struct ConnectionStats {
+    handle: StatsHandle,
+    id: u64,
+    pub peer: SocketAddr,
+    /// Адрес прокси, к которому подключился клиент
+    pub local: SocketAddr,
+    bytes: Arc<AtomicU64>,
+    /// Клиент получил ответ об успехе, и сессия не оборвалась с ошибкой
+    succeeded: AtomicBool,
+}
+
+impl ConnectionStats {
+    pub fn set_target(&self, target: SocketAddr) {
+        if let Some(info) = self.handle.lock().iter_mut().find(|info| info.id == self.id) {
+            info.target = Some(target);
+        }
+    }
+
+    /// Счётчик переданных байт, который ведёт ретрансляция
+    pub fn bytes(&self) -> &AtomicU64 {
+        &self.bytes
+    }
+
+    pub fn set_succeeded(&self) {
+        self.succeeded.store(true, Ordering::Relaxed);
+    }
+
+    /// Закончилась ли сессия успешно; иначе соединение закрывается сбросом
+    pub fn succeeded(&self) -> bool {
+        self.succeeded.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for ConnectionStats {
+    fn drop(&mut self) {
+        self.handle.lock().retain(|info| info.id != self.id);
+    }
+}
+
+
+/// Отдаёт снимок в JSON каждому, кто подключится к Unix сокету `path`, и
+/// закрывает соединение. Файл, оставшийся от прошлого запуска, заменяется
+#[cfg(unix)]
+pub(crate) fn listen(path: &Path, stats: StatsHandle) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::UnixListener;
+    use tracing::{debug, warn};
+
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((mut stream, _)) => {
+                    if let Err(e) = stream.write_all(stats.render_json().as_bytes()).await {
+                        debug!("stats request error: {}", e);
+                    }
+                }
+                Err(e) => warn!("stats accept error: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn listen(_path: &Path, _stats: StatsHandle) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets are not supported on this platform"))
+}
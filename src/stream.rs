@@ -0,0 +1,103 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+use tokio::net::TcpStream;
+
+
+/// Соединение с клиентом: обычный TCP, TCP, обёрнутый в TLS, или Unix сокет.
+/// Обработчики SOCKS работают только через этот интерфейс, поэтому
+/// шифрование для них прозрачно
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Сколько полученных от клиента байт ещё не прочитано из буфера
+    fn buffered(&self) -> usize {
+        0
+    }
+
+    /// SO_LINGER сокета; у Unix сокета TIME_WAIT нет, и он не меняется
+    fn set_linger(&self, _linger: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ClientStream for TcpStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        SockRef::from(self).set_linger(linger)
+    }
+}
+
+impl ClientStream for tokio_rustls::server::TlsStream<TcpStream> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+
+    fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        SockRef::from(self.get_ref().0).set_linger(linger)
+    }
+}
+
+/// У клиентов Unix сокета нет IP адресов, оба адреса - `UNIX_CLIENT_ADDR`
+#[cfg(unix)]
+impl ClientStream for tokio::net::UnixStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(crate::unix_socket::UNIX_CLIENT_ADDR)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(crate::unix_socket::UNIX_CLIENT_ADDR)
+    }
+}
+
+impl<S: ClientStream + ?Sized> ClientStream for Box<S> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        (**self).local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    fn buffered(&self) -> usize {
+        (**self).buffered()
+    }
+
+    fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        (**self).set_linger(linger)
+    }
+}
+
+/// Буфер позволяет посмотреть первый байт (версию протокола), не извлекая его
+impl<S: ClientStream> ClientStream for BufReader<S> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    fn buffered(&self) -> usize {
+        self.buffer().len() + self.get_ref().buffered()
+    }
+
+    fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        self.get_ref().set_linger(linger)
+    }
+}
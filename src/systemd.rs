@@ -0,0 +1,51 @@
+use std::io;
+use std::net::TcpListener;
+
+
+/// Первый дескриптор, который systemd передаёт при активации сокетом
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+
+/// Слушающие сокеты, созданные systemd (`LISTEN_FDS`/`LISTEN_PID`, см.
+/// sd_listen_fds(3)). Пустой список, если процесс запущен не через активацию.
+/// Переменные окружения удаляются, чтобы дескрипторы не были взяты дважды
+#[cfg(unix)]
+pub fn listen_fds() -> io::Result<Vec<TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    let Ok(count) = std::env::var("LISTEN_FDS") else {
+        return Ok(Vec::new());
+    };
+    // Переменные могли достаться по наследству от родителя, которому они предназначались
+    let for_us = std::env::var("LISTEN_PID").is_ok_and(|pid| pid.parse() == Ok(std::process::id()));
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if !for_us {
+        return Ok(Vec::new());
+    }
+    let count: i32 = count
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid LISTEN_FDS {:?}", count)))?;
+
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // SAFETY: systemd передаёт дескрипторы LISTEN_FDS_START.. во владение
+        // процессу, а переменные удалены выше, так что второй раз их не взять
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        // Датаграммные и Unix сокеты (ListenDatagram=, ListenStream=/path) здесь бесполезны
+        let is_tcp = listener.local_addr().is_ok() && socket2::SockRef::from(&listener).r#type()? == socket2::Type::STREAM;
+        if !is_tcp {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Socket-activated fd {} is not a TCP socket", fd)));
+        }
+        listener.set_nonblocking(true)?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+#[cfg(not(unix))]
+pub fn listen_fds() -> io::Result<Vec<TcpListener>> {
+    Ok(Vec::new())
+}
@@ -0,0 +1,26 @@
+use std::path::Path;
+use std::sync::Arc;
+use anyhow::{Context, Result};
+use rustls::ServerConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+
+
+/// Настройки TLS сервера из PEM файлов: цепочки сертификатов и закрытого ключа
+pub fn load_server_config(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<Arc<ServerConfig>> {
+    let cert_path = cert_path.as_ref();
+    let key_path = key_path.as_ref();
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Cannot read TLS certificates from {}", cert_path.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("Cannot read TLS private key from {}", key_path.display()))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("TLS certificate does not match the private key")?;
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,490 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use tokio::io::AsyncReadExt;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+use crate::{canonical_ip, reply, push_socket_addr, SocksError, SOCKSReply};
+use crate::parse::{parse_address, Address, ParseError};
+use crate::acl::AccessControl;
+use crate::dns::{self, DnsResolver};
+use crate::metrics::Metrics;
+use crate::port_range::{self, PortRange};
+use crate::stream::ClientStream;
+
+
+/// Наибольший размер UDP датаграммы
+pub const MAX_DATAGRAM_SIZE: usize = 65535;
+/// Меньше не вместить заголовок с самым длинным доменом и хоть какие-то данные
+pub const MIN_DATAGRAM_SIZE: usize = 512;
+
+/// Сколько помнить результат резолвинга домена из датаграмм
+const NAME_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Сколько доменов помнит одна ассоциация
+const NAME_CACHE_SIZE: usize = 256;
+
+/// Как часто ассоциация пишет в лог об отброшенных датаграммах с чужих адресов
+const REJECTED_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Сколько по умолчанию помнить собеседника без датаграмм в обе стороны
+pub const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_secs(60);
+/// Сколько по умолчанию собеседников у одной ассоциации
+pub const DEFAULT_MAX_PEERS: usize = 1024;
+/// Не меньше стольких ждём ответа DNS серверу (порт 53) на последний запрос:
+/// рекурсивные резолверы иногда отвечают долго
+const PENDING_DNS_TIMEOUT: Duration = Duration::from_secs(120);
+/// Как часто забывать простаивающих собеседников
+const PEER_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+
+/// Адрес назначения из заголовка UDP датаграммы
+pub type UdpTarget<'a> = Address<'a>;
+
+/// Разбирает заголовок UDP запроса SOCKS5: RSV(2), FRAG, ATYP, DST.ADDR, DST.PORT.
+/// Возвращает адрес назначения и смещение начала данных. Только разбор, без
+/// резолвинга и ввода-вывода: на любых входных данных возвращает ошибку, а не паникует
+pub fn parse_udp_header(datagram: &[u8]) -> Result<(UdpTarget<'_>, usize)> {
+    if datagram.len() < 4 {
+        return Err(SocksError::MalformedDatagram.into());
+    }
+    // Сборку фрагментов не поддерживаем
+    if datagram[2] != 0x00 {
+        return Err(SocksError::FragmentedDatagram.into());
+    }
+
+    // Датаграмма приходит целиком, так что нехватка байт - тоже порча заголовка
+    match parse_address(&datagram[3..]) {
+        Ok((target, len)) => Ok((target, 3 + len)),
+        Err(ParseError::Malformed(e)) if matches!(e.downcast_ref(), Some(SocksError::UnsupportedAddressType)) => Err(e),
+        Err(_) => Err(SocksError::MalformedDatagram.into()),
+    }
+}
+
+/// Заголовок для датаграммы, пришедшей клиенту от `source`
+pub fn encode_udp_header(source: &SocketAddr) -> Vec<u8> {
+    let mut header = vec![0x00, 0x00, 0x00];
+    push_socket_addr(&mut header, source);
+    header
+}
+
+
+/// Результаты резолвинга доменов из датаграмм одной ассоциации, чтобы не
+/// спрашивать DNS на каждый пакет. Неудачи тоже запоминаются: датаграммы на
+/// несуществующее имя отбрасываются без повторных запросов
+struct NameCache {
+    entries: HashMap<Vec<u8>, (Option<IpAddr>, Instant)>,
+}
+
+impl NameCache {
+    fn new() -> NameCache {
+        NameCache { entries: HashMap::new() }
+    }
+
+    /// Адрес домена из кэша или резолвера; `None`, если имя не резолвится
+    async fn resolve(&mut self, domain: &[u8], dns: Option<&DnsResolver>) -> Option<IpAddr> {
+        let now = Instant::now();
+        if let Some(&(ip, expires)) = self.entries.get(domain) {
+            if expires > now {
+                return ip;
+            }
+        }
+        let ip = match dns::resolve(domain, 0, dns).await {
+            Ok(addr) => Some(addr.ip()),
+            Err(e) => {
+                debug!("udp destination {} not resolved: {}", String::from_utf8_lossy(domain), e);
+                None
+            }
+        };
+        if self.entries.len() >= NAME_CACHE_SIZE {
+            self.entries.retain(|_, (_, expires)| *expires > now);
+            if self.entries.len() >= NAME_CACHE_SIZE {
+                self.entries.clear();
+            }
+        }
+        self.entries.insert(domain.to_vec(), (ip, now + NAME_CACHE_TTL));
+        ip
+    }
+}
+
+
+/// Настройки одной ассоциации
+#[derive(Debug, Clone, Copy)]
+pub struct UdpOptions {
+    /// Сколько помнить собеседника, с которым не было обмена датаграммами
+    pub peer_timeout: Duration,
+    /// Сверх этого числа забывается собеседник, простаивающий дольше всех
+    pub max_peers: usize,
+    /// Наибольшая датаграмма между клиентом и ретранслятором, с заголовком
+    /// SOCKS; датаграммы больше отбрасываются, а не обрезаются
+    pub max_datagram_size: usize,
+    /// IP, который клиент получает в BND.ADDR вместо адреса сокета
+    /// ретранслятора, если тот за NAT
+    pub external_ip: Option<IpAddr>,
+    /// Порты сокета ретранслятора для клиента; без них порт выбирает система
+    pub ports: Option<PortRange>,
+}
+
+impl Default for UdpOptions {
+    fn default() -> UdpOptions {
+        UdpOptions {
+            peer_timeout: DEFAULT_PEER_TIMEOUT,
+            max_peers: DEFAULT_MAX_PEERS,
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+            external_ip: None,
+            ports: None,
+        }
+    }
+}
+
+
+/// Собеседники ассоциации: адреса, куда клиент слал датаграммы. Ответы
+/// принимаются только от них, пока запись не забыта
+struct PeerTable {
+    peers: HashMap<SocketAddr, Peer>,
+    limits: UdpOptions,
+}
+
+struct Peer {
+    last: Instant,
+    /// Последней была датаграмма клиента, ответа на неё ещё не было
+    awaiting_reply: bool,
+}
+
+impl PeerTable {
+    fn new(limits: UdpOptions) -> PeerTable {
+        PeerTable { peers: HashMap::new(), limits }
+    }
+
+    fn expired(&self, addr: &SocketAddr, peer: &Peer, now: Instant) -> bool {
+        let timeout = match addr.port() {
+            53 if peer.awaiting_reply => self.limits.peer_timeout.max(PENDING_DNS_TIMEOUT),
+            _ => self.limits.peer_timeout,
+        };
+        now.duration_since(peer.last) > timeout
+    }
+
+    /// Клиент отправил датаграмму `addr`. Возвращает, сколько собеседников
+    /// пришлось забыть, чтобы уложиться в лимит
+    fn sent(&mut self, addr: SocketAddr, now: Instant) -> usize {
+        let mut evicted = 0;
+        if !self.peers.contains_key(&addr) && self.peers.len() >= self.limits.max_peers {
+            let oldest = self.peers.iter().min_by_key(|(_, peer)| peer.last).map(|(&addr, _)| addr);
+            if let Some(oldest) = oldest {
+                self.peers.remove(&oldest);
+                evicted += 1;
+            }
+        }
+        self.peers.insert(addr, Peer { last: now, awaiting_reply: true });
+        evicted
+    }
+
+    /// Пришла датаграмма от `addr`; `false`, если клиент ему не писал
+    /// или запись уже устарела
+    fn received(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        match self.peers.get(&addr) {
+            Some(peer) if !self.expired(&addr, peer, now) => {
+                self.peers.insert(addr, Peer { last: now, awaiting_reply: false });
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Забывает простаивающих собеседников; возвращает, сколько забыто
+    fn sweep(&mut self, now: Instant) -> usize {
+        let before = self.peers.len();
+        let expired: Vec<SocketAddr> = self.peers.iter().filter(|(addr, peer)| self.expired(addr, peer, now)).map(|(&addr, _)| addr).collect();
+        for addr in expired {
+            self.peers.remove(&addr);
+        }
+        before - self.peers.len()
+    }
+}
+
+
+/// Ретранслятор одной UDP ассоциации. Клиент шлёт датаграммы на `client_socket`,
+/// наружу они уходят через отдельные сокеты для IPv4 и IPv6
+pub struct UdpRelay {
+    client_socket: UdpSocket,
+    remote_v4: UdpSocket,
+    remote_v6: Option<UdpSocket>,
+    /// С какого адреса принимаются датаграммы клиента; порт 0 - с любого порта
+    allowed_source: SocketAddr,
+    /// Адрес клиента, определённый по первой принятой датаграмме
+    client_addr: Option<SocketAddr>,
+    /// Сколько датаграмм отброшено из-за адреса отправителя и когда об этом писали в лог
+    rejected: u64,
+    rejected_logged: Option<Instant>,
+    /// Правила для адресов назначения датаграмм
+    acl: AccessControl,
+    dns: Option<DnsResolver>,
+    names: NameCache,
+    peers: PeerTable,
+    max_datagram_size: usize,
+    metrics: Arc<Metrics>,
+}
+
+impl UdpRelay {
+    /// `client_socket` - сокет для клиента на адресе, по которому клиент
+    /// достучался до сервера по TCP. `expected` - DST.ADDR/DST.PORT из запроса: с него клиент будет слать
+    /// датаграммы (RFC 1928). Нулевой IP означает IP клиента из TCP соединения,
+    /// нулевой порт - любой порт. Без `check_source` запрос игнорируется и
+    /// принимаются датаграммы с IP клиента с любого порта
+    pub async fn new(client_socket: UdpSocket, client_ip: IpAddr, expected: SocketAddr, check_source: bool, acl: AccessControl, dns: Option<DnsResolver>, metrics: Arc<Metrics>) -> Result<UdpRelay> {
+        let remote_v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        // IPv6 может быть недоступен на хосте, тогда ретранслируем только IPv4
+        let remote_v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok();
+        let allowed_source = match check_source {
+            true if !expected.ip().is_unspecified() => expected,
+            true => SocketAddr::new(client_ip, expected.port()),
+            false => SocketAddr::new(client_ip, 0),
+        };
+        Ok(UdpRelay {
+            client_socket,
+            remote_v4,
+            remote_v6,
+            allowed_source,
+            client_addr: None,
+            rejected: 0,
+            rejected_logged: None,
+            acl,
+            dns,
+            names: NameCache::new(),
+            peers: PeerTable::new(UdpOptions::default()),
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+            metrics,
+        })
+    }
+
+    pub fn with_limits(mut self, options: UdpOptions) -> UdpRelay {
+        self.peers = PeerTable::new(options);
+        self.max_datagram_size = options.max_datagram_size;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.client_socket.local_addr()
+    }
+
+    /// Ретранслирует датаграммы, пока открыто управляющее TCP соединение
+    pub async fn run(&mut self, control: &mut dyn ClientStream) -> Result<()> {
+        let mut control_buffer = [0; 512];
+        // Лишний байт отличает датаграмму предельного размера от обрезанной
+        let mut client_buffer = vec![0; self.max_datagram_size + 1];
+        let mut remote_buffer = vec![0; self.max_datagram_size + 1];
+        let mut sweep = tokio::time::interval(PEER_SWEEP_INTERVAL);
+        loop {
+            tokio::select! {
+                // Клиент не должен ничего слать по TCP; EOF завершает ассоциацию
+                read = control.read(&mut control_buffer) => match read {
+                    Ok(0) | Err(_) => return Ok(()),
+                    Ok(_) => {}
+                },
+                received = self.client_socket.recv_from(&mut client_buffer) => {
+                    if let Ok((n, source)) = received {
+                        if n > self.max_datagram_size {
+                            self.oversized(source);
+                        } else {
+                            self.forward_to_remote(&client_buffer[..n], source).await;
+                        }
+                    }
+                }
+                received = recv_remote(&self.remote_v4, self.remote_v6.as_ref(), &mut remote_buffer) => {
+                    if let Ok((n, source)) = received {
+                        self.forward_to_client(&remote_buffer[..n], source).await;
+                    }
+                }
+                _ = sweep.tick() => {
+                    let evicted = self.peers.sweep(Instant::now());
+                    if evicted > 0 {
+                        debug!("udp association forgot {} idle peers", evicted);
+                        self.metrics.udp_peers_evicted(evicted);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Датаграммы с чужих адресов отбрасываются; в лог об этом пишется
+    /// не чаще раза в `REJECTED_LOG_INTERVAL`, чтобы поток мусора его не забил
+    fn source_rejected(&mut self, source: SocketAddr) {
+        self.rejected += 1;
+        let now = Instant::now();
+        if self.rejected_logged.is_none_or(|logged| now.duration_since(logged) >= REJECTED_LOG_INTERVAL) {
+            warn!(
+                "udp datagram from {} dropped: client announced {} ({} dropped so far)",
+                source, self.allowed_source, self.rejected
+            );
+            self.rejected_logged = Some(now);
+        }
+    }
+
+    async fn forward_to_remote(&mut self, datagram: &[u8], source: SocketAddr) {
+        // Ответы уходят на адрес первой принятой датаграммы, поэтому и
+        // дальше принимаются датаграммы только с него
+        let accepted = match self.client_addr {
+            Some(client_addr) => client_addr == source,
+            None => {
+                canonical_ip(source.ip()) == canonical_ip(self.allowed_source.ip())
+                    && (self.allowed_source.port() == 0 || self.allowed_source.port() == source.port())
+            }
+        };
+        if !accepted {
+            self.source_rejected(source);
+            return;
+        }
+        self.client_addr = Some(source);
+
+        let (target, offset) = match parse_udp_header(datagram) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                if matches!(e.downcast_ref(), Some(SocksError::FragmentedDatagram)) {
+                    self.metrics.fragmented_datagram();
+                }
+                debug!("udp datagram dropped: {}", e);
+                return;
+            }
+        };
+        let target = match target {
+            UdpTarget::Ip(addr) => addr,
+            // Ассоциация продолжает работать, теряется только эта датаграмма
+            UdpTarget::Domain(domain, port) => match self.names.resolve(domain, self.dns.as_ref()).await {
+                Some(ip) => SocketAddr::new(ip, port),
+                None => {
+                    self.metrics.unresolved_datagram();
+                    return;
+                }
+            },
+        };
+        if target.port() == 0 {
+            debug!("udp datagram to {} dropped: {}", target, SocksError::ZeroPort);
+            return;
+        }
+        if !self.acl.is_allowed(&target) {
+            warn!("udp datagram to {} denied by rules", target);
+            return;
+        }
+
+        let socket = match target {
+            SocketAddr::V4(_) => Some(&self.remote_v4),
+            SocketAddr::V6(_) => self.remote_v6.as_ref(),
+        };
+        if let Some(socket) = socket {
+            let evicted = self.peers.sent(target, Instant::now());
+            if evicted > 0 {
+                debug!("udp association is at its peer limit, forgot the longest idle peer");
+                self.metrics.udp_peers_evicted(evicted);
+            }
+            let _ = socket.send_to(&datagram[offset..], target).await;
+        }
+    }
+
+    /// Ответы принимаются только от тех, кому клиент писал, иначе через
+    /// ассоциацию клиенту мог бы слать датаграммы кто угодно
+    async fn forward_to_client(&mut self, payload: &[u8], source: SocketAddr) {
+        let source = SocketAddr::new(canonical_ip(source.ip()), source.port());
+        if !self.peers.received(source, Instant::now()) {
+            debug!("udp datagram from {} dropped: not a peer of this association", source);
+            return;
+        }
+        if let Some(client_addr) = self.client_addr {
+            let mut datagram = encode_udp_header(&source);
+            datagram.extend_from_slice(payload);
+            // Вместе с заголовком ответ может не влезть в предел для клиента
+            if datagram.len() > self.max_datagram_size {
+                self.oversized(source);
+                return;
+            }
+            let _ = self.client_socket.send_to(&datagram, client_addr).await;
+        }
+    }
+
+    fn oversized(&self, source: SocketAddr) {
+        debug!("udp datagram from {} dropped: larger than {} bytes", source, self.max_datagram_size);
+        self.metrics.oversized_datagram();
+    }
+}
+
+
+/// Ответ цели на любом из внешних сокетов
+async fn recv_remote(remote_v4: &UdpSocket, remote_v6: Option<&UdpSocket>, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match remote_v6 {
+        Some(remote_v6) => tokio::select! {
+            _ = remote_v4.readable() => remote_v4.try_recv_from(buffer),
+            _ = remote_v6.readable() => remote_v6.try_recv_from(buffer),
+        },
+        None => remote_v4.recv_from(buffer).await,
+    }
+}
+
+
+/// UDP ASSOCIATE: поднимает ретранслятор, сообщает клиенту его адрес
+/// (с внешним IP, если он задан) и обслуживает ассоциацию до закрытия
+/// управляющего соединения
+pub async fn process_udp_associate(client_stream: &mut dyn ClientStream, expected: SocketAddr, check_source: bool, acl: &AccessControl, dns: Option<&DnsResolver>, options: UdpOptions, metrics: &Arc<Metrics>) -> Result<()> {
+    let local_ip = client_stream.local_addr()?.ip();
+    let client_ip = client_stream.peer_addr()?.ip();
+    let client_socket = port_range::bind_in(options.ports, |port| UdpSocket::bind((local_ip, port))).await?;
+    let mut relay = UdpRelay::new(client_socket, client_ip, expected, check_source, acl.clone(), dns.cloned(), Arc::clone(metrics))
+        .await?
+        .with_limits(options);
+    let relay_addr = relay.local_addr()?;
+    // Порт за NAT тот же: правила DNAT пишутся на диапазон портов один к одному
+    let advertised = SocketAddr::new(options.external_ip.unwrap_or(relay_addr.ip()), relay_addr.port());
+    if advertised == relay_addr {
+        info!("udp relay listening on {}", relay_addr);
+    } else {
+        info!("udp relay listening on {} (advertised as {})", relay_addr, advertised);
+    }
+    reply(client_stream, SOCKSReply::Succeeded, &advertised).await?;
+    relay.run(client_stream).await
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(datagram: &[u8]) -> SocksError {
+        parse_udp_header(datagram).unwrap_err().downcast().expect("SocksError")
+    }
+
+    #[test]
+    fn header_ipv4() {
+        let datagram = [0x00, 0x00, 0x00, 0x01, 8, 8, 8, 8, 0x00, 0x35, b'd', b'n', b's'];
+        let (target, offset) = parse_udp_header(&datagram).unwrap();
+        assert_eq!(target, Address::Ip("8.8.8.8:53".parse().unwrap()));
+        assert_eq!(&datagram[offset..], b"dns");
+    }
+
+    #[test]
+    fn header_domain() {
+        let datagram = b"\x00\x00\x00\x03\x0bexample.com\x01\xbbdata";
+        let (target, offset) = parse_udp_header(datagram).unwrap();
+        assert_eq!(target, Address::Domain(b"example.com", 443));
+        assert_eq!(&datagram[offset..], b"data");
+    }
+
+    #[test]
+    fn header_malformed() {
+        assert!(matches!(error(&[0x00, 0x00, 0x00]), SocksError::MalformedDatagram));
+        assert!(matches!(error(&[0x00, 0x00, 0x00, 0x01, 8, 8]), SocksError::MalformedDatagram));
+        assert!(matches!(error(b"\x00\x00\x00\x03\x0bexample"), SocksError::MalformedDatagram));
+        assert!(matches!(error(&[0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x35]), SocksError::MalformedDatagram));
+        assert!(matches!(error(&[0x00, 0x00, 0x01, 0x01, 8, 8, 8, 8, 0x00, 0x35]), SocksError::FragmentedDatagram));
+        assert!(matches!(error(&[0x00, 0x00, 0x00, 0x02, 8, 8, 8, 8, 0x00, 0x35]), SocksError::UnsupportedAddressType));
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let source: SocketAddr = "[2001:db8::2]:5353".parse().unwrap();
+        let mut datagram = encode_udp_header(&source);
+        datagram.extend_from_slice(b"reply");
+        let (target, offset) = parse_udp_header(&datagram).unwrap();
+        assert_eq!(target, Address::Ip(source));
+        assert_eq!(&datagram[offset..], b"reply");
+    }
+}
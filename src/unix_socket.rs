@@ -0,0 +1,75 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use crate::stream::ClientStream;
+
+
+/// Адрес, под которым клиенты Unix сокета видны в логах, статистике и
+/// лимитах соединений: у них нет IP, а обработчикам нужен `SocketAddr`
+pub const UNIX_CLIENT_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+
+/// Слушатель SOCKS на Unix сокете. Файл сокета удаляется, когда слушатель
+/// закрывается, а файл, оставшийся от прошлого запуска, заменяется
+#[cfg(unix)]
+pub(crate) struct UnixSocketListener {
+    listener: tokio::net::UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocketListener {
+    /// Права `mode` и владелец `uid`/`gid` ставятся сразу после создания файла
+    pub(crate) fn bind(path: &Path, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>) -> io::Result<UnixSocketListener> {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        let listener = tokio::net::UnixListener::bind(path)?;
+        let listener = UnixSocketListener { listener, path: path.to_owned() };
+        if let Some(mode) = mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        if uid.is_some() || gid.is_some() {
+            std::os::unix::fs::chown(path, uid, gid)?;
+        }
+        Ok(listener)
+    }
+
+    pub(crate) async fn accept(&self) -> io::Result<Box<dyn ClientStream>> {
+        let (stream, _) = self.listener.accept().await?;
+        Ok(Box::new(stream))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+
+#[cfg(not(unix))]
+pub(crate) struct UnixSocketListener(std::convert::Infallible);
+
+#[cfg(not(unix))]
+impl UnixSocketListener {
+    pub(crate) fn bind(_path: &Path, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>) -> io::Result<UnixSocketListener> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets are not supported on this platform"))
+    }
+
+    pub(crate) async fn accept(&self) -> io::Result<Box<dyn ClientStream>> {
+        match self.0 {}
+    }
+}
+
+
+/// Принимает соединение, если слушатель задан; иначе никогда не завершается
+pub(crate) async fn accept_optional(listener: Option<&UnixSocketListener>) -> io::Result<Box<dyn ClientStream>> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,473 @@
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use socks_beta::acl::AccessControl;
+use socks_beta::client::Socks5Stream;
+use socks_beta::port_range::PortRange;
+use socks_beta::{SocksError, SocksServer};
+
+
+/// Запускает сервер на свободном порту 127.0.0.1 и ждёт, пока он начнёт принимать клиентов
+async fn start_server(acl: AccessControl) -> SocketAddr {
+    start_configured(|server| server.with_access_control(acl)).await
+}
+
+/// Как `start_server`, но настройки сервера задаёт `configure`
+async fn start_configured(configure: impl FnOnce(SocksServer) -> SocksServer) -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let server = configure(SocksServer::new(addr).with_listener(listener)).with_ready_signal(ready_tx);
+    tokio::spawn(server.run());
+    tokio::task::spawn_blocking(move || ready_rx.recv()).await.unwrap().expect("server failed to start");
+    addr
+}
+
+/// Эхо-сервер на `addr`; возвращает его адрес с выбранным портом
+async fn start_echo(addr: &str) -> SocketAddr {
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    addr
+}
+
+async fn assert_echo(mut stream: TcpStream) {
+    let message = b"hello through socks";
+    stream.write_all(message).await.unwrap();
+    let mut received = [0; 19];
+    stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, message);
+}
+
+/// Отправляет запрос SOCKS5 без аутентификации и возвращает поток и первый ответ
+async fn socks5_request(proxy: SocketAddr, command: u8, target: SocketAddr) -> (TcpStream, u8, SocketAddr) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut method = [0; 2];
+    stream.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [0x05, 0x00]);
+    let mut request = vec![0x05, command, 0x00];
+    push_addr(&mut request, target);
+    stream.write_all(&request).await.unwrap();
+    let (code, addr) = read_reply(&mut stream).await;
+    (stream, code, addr)
+}
+
+fn push_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr {
+        SocketAddr::V4(v4) => {
+            buf.push(0x01);
+            buf.extend_from_slice(&v4.ip().octets());
+        }
+        SocketAddr::V6(v6) => {
+            buf.push(0x04);
+            buf.extend_from_slice(&v6.ip().octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Ответ SOCKS5: REP и BND.ADDR:BND.PORT; VER и RSV проверяются
+async fn read_reply(stream: &mut TcpStream) -> (u8, SocketAddr) {
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await.unwrap();
+    assert_eq!((header[0], header[2]), (0x05, 0x00));
+    let ip = match header[3] {
+        0x01 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await.unwrap();
+            std::net::IpAddr::from(ip)
+        }
+        0x04 => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip).await.unwrap();
+            std::net::IpAddr::from(ip)
+        }
+        atyp => panic!("unexpected ATYP {:#04x}", atyp),
+    };
+    let mut port = [0; 2];
+    stream.read_exact(&mut port).await.unwrap();
+    (header[1], SocketAddr::new(ip, u16::from_be_bytes(port)))
+}
+
+/// Два свободных подряд порта на 127.0.0.1
+fn free_port_pair() -> u16 {
+    loop {
+        let first = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = first.local_addr().unwrap().port();
+        if port < u16::MAX && std::net::TcpListener::bind(("127.0.0.1", port + 1)).is_ok() {
+            return port;
+        }
+    }
+}
+
+/// Код ответа сервера, с которым клиент не смог подключиться
+fn reply_code(result: anyhow::Result<TcpStream>) -> u8 {
+    match result.map_err(|e| e.downcast::<SocksError>()) {
+        Err(Ok(SocksError::UpstreamFailure(code))) => code,
+        Err(Ok(e)) => panic!("unexpected error: {}", e),
+        Err(Err(e)) => panic!("unexpected error: {:#}", e),
+        Ok(_) => panic!("connection unexpectedly succeeded"),
+    }
+}
+
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connect_ipv4() {
+    let proxy = start_server(AccessControl::new()).await;
+    let echo = start_echo("127.0.0.1:0").await;
+    assert_echo(Socks5Stream::connect(proxy, echo).await.unwrap()).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connect_ipv6() {
+    let proxy = start_server(AccessControl::new()).await;
+    let echo = start_echo("[::1]:0").await;
+    assert_echo(Socks5Stream::connect(proxy, echo).await.unwrap()).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connect_with_name() {
+    let proxy = start_server(AccessControl::new()).await;
+    let echo = start_echo("127.0.0.1:0").await;
+    assert_echo(Socks5Stream::connect_with_name(proxy, "localhost", echo.port()).await.unwrap()).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn refused_target() {
+    let proxy = start_server(AccessControl::new()).await;
+    // Порт, который только что был занят и освобождён, никто не слушает
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    assert_eq!(reply_code(Socks5Stream::connect(proxy, closed).await), 0x05);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn denied_target() {
+    let mut acl = AccessControl::new();
+    acl.deny("127.0.0.0/8").unwrap();
+    acl.deny("::1/128").unwrap();
+    let proxy = start_server(acl).await;
+    let echo = start_echo("127.0.0.1:0").await;
+    assert_eq!(reply_code(Socks5Stream::connect(proxy, echo).await), 0x02);
+    assert_eq!(reply_code(Socks5Stream::connect_with_name(proxy, "localhost", echo.port()).await), 0x02);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bind_port_range_exhausted() {
+    let first = free_port_pair();
+    let proxy = start_configured(|server| server.with_bind(true).with_bind_port_range(PortRange::new(first, first + 1))).await;
+    let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+    let (_a, code, a) = socks5_request(proxy, 0x02, unspecified).await;
+    assert_eq!(code, 0x00);
+    let (_b, code, b) = socks5_request(proxy, 0x02, unspecified).await;
+    assert_eq!(code, 0x00);
+    let mut ports = [a.port(), b.port()];
+    ports.sort();
+    assert_eq!(ports, [first, first + 1]);
+    // Оба порта заняты слушателями первых двух запросов
+    let (_c, code, _) = socks5_request(proxy, 0x02, unspecified).await;
+    assert_eq!(code, 0x01);
+}
+
+/// Запрос SOCKS4 CONNECT (с доменом SOCKS4a, если он задан); возвращает поток и ответ
+async fn socks4_connect(proxy: SocketAddr, target: SocketAddr, domain: Option<&str>) -> (TcpStream, [u8; 8]) {
+    let SocketAddr::V4(target) = target else {
+        panic!("SOCKS4 needs an IPv4 target");
+    };
+    let mut request = vec![0x04, 0x01];
+    request.extend_from_slice(&target.port().to_be_bytes());
+    match domain {
+        Some(_) => request.extend_from_slice(&[0, 0, 0, 1]),
+        None => request.extend_from_slice(&target.ip().octets()),
+    }
+    request.extend_from_slice(b"user\0");
+    if let Some(domain) = domain {
+        request.extend_from_slice(domain.as_bytes());
+        request.push(0);
+    }
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0; 8];
+    stream.read_exact(&mut reply).await.unwrap();
+    (stream, reply)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn socks4_connect_and_reject() {
+    let proxy = start_server(AccessControl::new()).await;
+    let echo = start_echo("127.0.0.1:0").await;
+
+    let (stream, reply) = socks4_connect(proxy, echo, None).await;
+    assert_eq!(reply[..2], [0x00, 0x5A]);
+    assert_echo(stream).await;
+
+    let (stream, reply) = socks4_connect(proxy, echo, Some("localhost")).await;
+    assert_eq!(reply[..2], [0x00, 0x5A]);
+    assert_echo(stream).await;
+
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let (_, reply) = socks4_connect(proxy, closed, None).await;
+    assert_eq!(reply[..2], [0x00, 0x5B]);
+    let (_, reply) = socks4_connect(proxy, echo, Some("example.invalid")).await;
+    assert_eq!(reply[..2], [0x00, 0x5B]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn interleaved_socks4_and_socks5() {
+    let proxy = start_server(AccessControl::new()).await;
+    let echo = start_echo("127.0.0.1:0").await;
+
+    // Клиент SOCKS5 прислал только приветствие, пока обслуживается клиент SOCKS4
+    let mut v5 = TcpStream::connect(proxy).await.unwrap();
+    v5.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let (v4, reply) = socks4_connect(proxy, echo, None).await;
+    assert_eq!(reply[..2], [0x00, 0x5A]);
+    let mut method = [0; 2];
+    v5.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [0x05, 0x00]);
+    let mut request = vec![0x05, 0x01, 0x00];
+    push_addr(&mut request, echo);
+    v5.write_all(&request).await.unwrap();
+    assert_eq!(read_reply(&mut v5).await.0, 0x00);
+    assert_echo(v5).await;
+    assert_echo(v4).await;
+
+    // И вперемешку параллельно
+    let clients: Vec<_> = (0..20)
+        .map(|i| tokio::spawn(async move {
+            if i % 2 == 0 {
+                let (stream, reply) = socks4_connect(proxy, echo, None).await;
+                assert_eq!(reply[1], 0x5A);
+                assert_echo(stream).await;
+            } else {
+                assert_echo(Socks5Stream::connect(proxy, echo).await.unwrap()).await;
+            }
+        }))
+        .collect();
+    for client in clients {
+        client.await.unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pipelined_handshake_and_payload() {
+    let proxy = start_configured(|server| server.with_auth("user", "secret")).await;
+    let echo = start_echo("127.0.0.1:0").await;
+
+    // Приветствие, аутентификация, запрос и первые данные одной записью
+    let mut message = vec![0x05, 0x01, 0x02];
+    message.extend_from_slice(b"\x01\x04user\x06secret");
+    message.extend_from_slice(&[0x05, 0x01, 0x00]);
+    push_addr(&mut message, echo);
+    message.extend_from_slice(b"early data");
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&message).await.unwrap();
+
+    let mut replies = [0; 4];
+    stream.read_exact(&mut replies).await.unwrap();
+    assert_eq!(replies, [0x05, 0x02, 0x01, 0x00]);
+    assert_eq!(read_reply(&mut stream).await.0, 0x00);
+    let mut received = [0; 10];
+    stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"early data");
+    assert_echo(stream).await;
+}
+
+/// DNS сервер по TCP, отвечающий на запросы A адресами `v4`, на остальные - пустым ответом
+async fn start_dns(v4: Vec<[u8; 4]>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let v4 = v4.clone();
+            tokio::spawn(async move {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).await.unwrap();
+                let mut query = vec![0; u16::from_be_bytes(len) as usize];
+                stream.read_exact(&mut query).await.unwrap();
+                // QTYPE - предпоследние два байта вопроса
+                let qtype = u16::from_be_bytes([query[query.len() - 4], query[query.len() - 3]]);
+                let answers = if qtype == 1 { v4 } else { Vec::new() };
+                let mut response = query;
+                response[2..4].copy_from_slice(&[0x81, 0x80]);
+                response[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+                for ip in answers {
+                    // Имя - ссылка на вопрос, TYPE A, CLASS IN, TTL, RDLENGTH 4
+                    response.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                    response.extend_from_slice(&ip);
+                }
+                let mut packet = (response.len() as u16).to_be_bytes().to_vec();
+                packet.extend_from_slice(&response);
+                stream.write_all(&packet).await.unwrap();
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connect_falls_back_to_next_address() {
+    let echo = start_echo("127.0.0.1:0").await;
+    // На 127.0.0.2 порт эхо-сервера никто не слушает
+    let dns = start_dns(vec![[127, 0, 0, 2], [127, 0, 0, 1]]).await;
+    let proxy = start_configured(|server| server.with_dns(dns)).await;
+    assert_echo(Socks5Stream::connect_with_name(proxy, "service.test", echo.port()).await.unwrap()).await;
+
+    // Если не удалось ни к одному, код ответа - от последней попытки
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    assert_eq!(reply_code(Socks5Stream::connect_with_name(proxy, "service.test", closed.port()).await), 0x05);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connect_timeout() {
+    // Слушающий сокет с заполненной очередью не отвечает на SYN - как недоступный адрес
+    let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+    socket.bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into()).unwrap();
+    socket.listen(0).unwrap();
+    let target = socket.local_addr().unwrap().as_socket().unwrap();
+    let mut backlog = Vec::new();
+    while let Ok(stream) = std::net::TcpStream::connect_timeout(&target, Duration::from_millis(200)) {
+        backlog.push(stream);
+    }
+
+    let proxy = start_configured(|server| server.with_connect_timeout(Duration::from_millis(300))).await;
+    let started = Instant::now();
+    let (_stream, code, _) = socks5_request(proxy, 0x01, target).await;
+    assert_eq!(code, 0x06);
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn udp_relay_closes_with_control_connection() {
+    let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 1024];
+        while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+            let _ = echo.send_to(&buf[..n], from).await;
+        }
+    });
+    let proxy = start_server(AccessControl::default()).await;
+    let (control, code, relay) = socks5_request(proxy, 0x03, "0.0.0.0:0".parse().unwrap()).await;
+    assert_eq!(code, 0x00);
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut datagram = vec![0, 0, 0];
+    push_addr(&mut datagram, echo_addr);
+    datagram.extend_from_slice(b"ping");
+    client.send_to(&datagram, relay).await.unwrap();
+    let mut buf = [0; 1024];
+    let n = tokio::time::timeout(Duration::from_secs(2), client.recv(&mut buf)).await.unwrap().unwrap();
+    assert_eq!(&buf[..n], &datagram[..]);
+
+    // После закрытия управляющего соединения сокет ретранслятора освобождается
+    assert!(UdpSocket::bind(relay).await.is_err());
+    drop(control);
+    let started = Instant::now();
+    while UdpSocket::bind(relay).await.is_err() {
+        assert!(started.elapsed() < Duration::from_secs(2), "relay socket still open");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rules_apply_to_resolved_addresses() {
+    let echo = start_echo("0.0.0.0:0").await;
+    let mut acl = AccessControl::new();
+    acl.deny("127.0.0.1/32").unwrap();
+    let denied = start_dns(vec![[127, 0, 0, 1]]).await;
+    let proxy = start_configured(|server| server.with_access_control(acl.clone()).with_dns(denied)).await;
+    assert_eq!(reply_code(Socks5Stream::connect_with_name(proxy, "service.test", echo.port()).await), 0x02);
+
+    // Запрещённый адрес пропускается, подключаемся к следующему
+    let mixed = start_dns(vec![[127, 0, 0, 1], [127, 0, 0, 2]]).await;
+    let proxy = start_configured(|server| server.with_access_control(acl).with_dns(mixed)).await;
+    assert_echo(Socks5Stream::connect_with_name(proxy, "service.test", echo.port()).await.unwrap()).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn greeting_without_methods() {
+    let proxy = start_server(AccessControl::new()).await;
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[0x05, 0x00]).await.unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, [0x05, 0xFF]);
+    // После отказа сервер закрывает соединение
+    let n = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut reply)).await.unwrap().unwrap();
+    assert_eq!(n, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unresolved_name() {
+    let proxy = start_server(AccessControl::new()).await;
+    assert_eq!(reply_code(Socks5Stream::connect_with_name(proxy, "example.invalid", 80).await), 0x04);
+    // Сервер продолжает работать после неудачного резолвинга
+    let echo = start_echo("127.0.0.1:0").await;
+    assert_echo(Socks5Stream::connect(proxy, echo).await.unwrap()).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn name_without_addresses() {
+    // Имя существует, но записей A и AAAA у него нет
+    let dns = start_dns(Vec::new()).await;
+    let proxy = start_configured(|server| server.with_dns(dns)).await;
+    assert_eq!(reply_code(Socks5Stream::connect_with_name(proxy, "service.test", 80).await), 0x03);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn handshake_timeout() {
+    let proxy = start_configured(|server| server.with_handshake_timeout(Duration::from_millis(200))).await;
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    // Приветствие обещает два метода, но приходит только один
+    stream.write_all(&[0x05, 0x02, 0x00]).await.unwrap();
+    let started = Instant::now();
+    let mut buf = [0; 16];
+    let n = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf)).await.unwrap().unwrap();
+    assert_eq!(n, 0);
+    assert!(started.elapsed() >= Duration::from_millis(150));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bind_accepts_peer() {
+    let proxy = start_configured(|server| server.with_bind(true)).await;
+    let (mut stream, code, listening) = socks5_request(proxy, 0x02, SocketAddr::from(([0, 0, 0, 0], 0))).await;
+    assert_eq!(code, 0x00);
+    assert_eq!(listening.ip(), proxy.ip());
+
+    let mut peer = TcpStream::connect(listening).await.unwrap();
+    let (code, peer_addr) = read_reply(&mut stream).await;
+    assert_eq!(code, 0x00);
+    assert_eq!(peer_addr, peer.local_addr().unwrap());
+
+    peer.write_all(b"from peer").await.unwrap();
+    let mut buf = [0; 9];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"from peer");
+    stream.write_all(b"to peer").await.unwrap();
+    let mut buf = [0; 7];
+    peer.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"to peer");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bind_timeout() {
+    let proxy = start_configured(|server| server.with_bind(true).with_bind_timeout(Duration::from_millis(200))).await;
+    let (mut stream, code, _) = socks5_request(proxy, 0x02, SocketAddr::from(([0, 0, 0, 0], 0))).await;
+    assert_eq!(code, 0x00);
+    // Никто не подключается: второй ответ - TTL expired
+    let (code, _) = tokio::time::timeout(Duration::from_secs(2), read_reply(&mut stream)).await.unwrap();
+    assert_eq!(code, 0x06);
+}